@@ -0,0 +1,238 @@
+// v-docs: a Model Context Protocol server serving V documentation to the
+// assistant.
+//
+// The extension writes this file to its work directory and Zed starts it as
+// `v run v_docs.v`, so it always documents the V that runs it.  Messages
+// are JSON-RPC, one per line on stdin and stdout.  Two tools are offered:
+// `v_doc` runs `v doc -f text` for a module or a symbol in it, and `v_modules`
+// lists the modules in vlib and in the VMODULES directory.  A failure is
+// returned as a tool error explaining it, so the assistant can say what
+// went wrong instead of guessing.
+module main
+
+import os
+import x.json2
+
+// Used when the client does not say which protocol version it speaks.
+const protocol_version = '2024-11-05'
+
+// Longest documentation returned.  Whole modules such as `os` run to
+// hundreds of kilobytes, which would crowd everything else out of the
+// assistant's context.
+const doc_max_bytes = 32 * 1024
+
+// Directories under a module root that hold no importable module.
+const skipped_dirs = ['tests', 'testdata', 'examples', 'docs', 'bin']
+
+fn main() {
+	for {
+		line := os.get_raw_line()
+		if line == '' {
+			break
+		}
+		if line.trim_space() == '' {
+			continue
+		}
+		request := json2.raw_decode(line) or {
+			send(error_response(json2.null, -32700, 'parse error: ${err}'))
+			continue
+		}
+		message := request.as_map()
+		// Notifications (`notifications/initialized`, …) have no id and get
+		// no reply.
+		id := message['id'] or { continue }
+		method := (message['method'] or { json2.Any('') }).str()
+		params := (message['params'] or { json2.Any(map[string]json2.Any{}) }).as_map()
+		send(handle(id, method, params))
+	}
+}
+
+fn handle(id json2.Any, method string, params map[string]json2.Any) map[string]json2.Any {
+	if method == 'initialize' {
+		version := (params['protocolVersion'] or { json2.Any(protocol_version) }).str()
+		return result(id, json2.Any({
+			'protocolVersion': json2.Any(version)
+			'capabilities':    json2.Any({
+				'tools': json2.Any(map[string]json2.Any{})
+			})
+			'serverInfo':      json2.Any({
+				'name':    json2.Any('v-docs')
+				'version': json2.Any('1.0.0')
+			})
+		}))
+	}
+	if method == 'ping' {
+		return result(id, json2.Any(map[string]json2.Any{}))
+	}
+	if method == 'tools/list' {
+		return result(id, json2.Any({
+			'tools': json2.Any(tools())
+		}))
+	}
+	if method == 'tools/call' {
+		name := (params['name'] or { json2.Any('') }).str()
+		args := (params['arguments'] or { json2.Any(map[string]json2.Any{}) }).as_map()
+		text := call_tool(name, args) or { return result(id, tool_result(err.msg(), true)) }
+		return result(id, tool_result(text, false))
+	}
+	return error_response(id, -32601, 'method not found: ${method}')
+}
+
+fn tools() []json2.Any {
+	return [
+		tool('v_doc', 'Show the documentation of a V module, or of one function, type or constant in it, as printed by `v doc`. Use it to check the real API and signatures before writing V code.',
+			{
+			'module': string_property('Module path, e.g. `os`, `strings` or `net.http`')
+			'symbol': string_property('Optional name in the module, e.g. `read_file` or `Builder`')
+		}, ['module']),
+		tool('v_modules', 'List the V modules that can be imported: the standard library in vlib, then the modules installed in the VMODULES directory.',
+			map[string]json2.Any{}, []string{}),
+	]
+}
+
+fn tool(name string, description string, properties map[string]json2.Any, required []string) json2.Any {
+	return json2.Any({
+		'name':        json2.Any(name)
+		'description': json2.Any(description)
+		'inputSchema': json2.Any({
+			'type':       json2.Any('object')
+			'properties': json2.Any(properties)
+			'required':   json2.Any(required.map(json2.Any(it)))
+		})
+	})
+}
+
+fn string_property(description string) json2.Any {
+	return json2.Any({
+		'type':        json2.Any('string')
+		'description': json2.Any(description)
+	})
+}
+
+fn call_tool(name string, args map[string]json2.Any) !string {
+	if name == 'v_doc' {
+		return v_doc(args)!
+	}
+	if name == 'v_modules' {
+		return v_modules()
+	}
+	return error('unknown tool `${name}`')
+}
+
+// ── Tools ─────────────────────────────────────────────────────────────────────
+
+fn v_doc(args map[string]json2.Any) !string {
+	mod_path := (args['module'] or { json2.Any('') }).str().trim_space()
+	if mod_path == '' {
+		return error('`module` is required, e.g. `os` or `net.http`')
+	}
+	symbol := (args['symbol'] or { json2.Any('') }).str().trim_space()
+	// The names go on a command line, so only plain names are accepted.
+	for name in [mod_path, symbol] {
+		if !is_name(name) {
+			return error('`${name}` is not a V name; use letters, digits, `_` and `.` only')
+		}
+	}
+	mut cmd := '${os.quoted_path(@VEXE)} doc -f text ${mod_path}'
+	if symbol != '' {
+		cmd += ' ${symbol}'
+	}
+	res := os.execute(cmd)
+	out := res.output.trim_space()
+	if res.exit_code != 0 || out == '' {
+		reason := if out == '' { 'no output' } else { out }
+		return error('v doc ${mod_path} ${symbol} failed: ${reason}\nUse v_modules to list the available modules.')
+	}
+	return truncate(out, doc_max_bytes)
+}
+
+fn v_modules() string {
+	vroot := os.getenv_opt('VROOT') or { @VEXEROOT }
+	vlib := os.join_path(vroot, 'vlib')
+	vmodules := os.vmodules_dir()
+	return module_list('Standard library (${vlib})', vlib) + '\n' +
+		module_list('Installed modules (${vmodules})', vmodules)
+}
+
+// ── Helpers ───────────────────────────────────────────────────────────────────
+
+fn result(id json2.Any, value json2.Any) map[string]json2.Any {
+	return {
+		'jsonrpc': json2.Any('2.0')
+		'id':      id
+		'result':  value
+	}
+}
+
+fn error_response(id json2.Any, code int, message string) map[string]json2.Any {
+	return {
+		'jsonrpc': json2.Any('2.0')
+		'id':      id
+		'error':   json2.Any({
+			'code':    json2.Any(code)
+			'message': json2.Any(message)
+		})
+	}
+}
+
+fn tool_result(text string, is_error bool) json2.Any {
+	return json2.Any({
+		'content': json2.Any([
+			json2.Any({
+				'type': json2.Any('text')
+				'text': json2.Any(text)
+			}),
+		])
+		'isError': json2.Any(is_error)
+	})
+}
+
+fn send(message map[string]json2.Any) {
+	println(json2.Any(message).json_str())
+	flush_stdout()
+}
+
+fn is_name(s string) bool {
+	return s.bytes().all(it.is_letter() || it.is_digit() || it == `_` || it == `.`)
+}
+
+// `docs` cut to at most `max` bytes at a line boundary, with a note saying
+// how much was left out.
+fn truncate(docs string, max int) string {
+	if docs.len <= max {
+		return docs
+	}
+	end := docs[..max].last_index('\n') or { max }
+	omitted := docs[end..].split_into_lines().filter(it != '').len
+	return '${docs[..end]}\n\n... truncated, ${omitted} more lines'
+}
+
+// The modules under `root`, one per line.  Nested modules such as
+// `crypto.sha256` are listed down to three levels.
+fn module_list(title string, root string) string {
+	if !os.is_dir(root) {
+		return '${title}: not found\n'
+	}
+	mut modules := []string{}
+	collect_modules(root, '', 0, mut modules)
+	modules.sort()
+	return '${title}:\n${modules.join('\n')}\n'
+}
+
+fn collect_modules(dir string, prefix string, depth int, mut modules []string) {
+	entries := os.ls(dir) or { return }
+	for entry in entries {
+		path := os.join_path(dir, entry)
+		if entry.starts_with('.') || entry in skipped_dirs || !os.is_dir(path) {
+			continue
+		}
+		name := if prefix == '' { entry } else { '${prefix}.${entry}' }
+		files := os.ls(path) or { []string{} }
+		if files.any(it.ends_with('.v') && !it.ends_with('_test.v')) {
+			modules << name
+		}
+		if depth < 2 {
+			collect_modules(path, name, depth + 1, mut modules)
+		}
+	}
+}
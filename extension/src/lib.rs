@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+use std::time::Duration;
 use zed_extension_api::{
     self as zed,
     LanguageServerId, Result,
@@ -10,13 +12,66 @@ use zed_extension_api::{
 const GITHUB_RELEASES_URL: &str =
     "https://api.github.com/repos/DaZhi-the-Revelator/velvet/releases/latest";
 
+// Repository prebuilt velvet binaries are downloaded from when velvet is not
+// installed.
+const VELVET_REPO: &str = "DaZhi-the-Revelator/velvet";
+
+// Where velvet is cloned and built when `lsp.velvet.settings.build_from_source`
+// is on, relative to the extension's work directory.  Kept out of the
+// `velvet-*` names so pruning old downloads leaves it alone.
+const VELVET_SOURCE_DIR: &str = "source/velvet";
+const VELVET_GIT_URL: &str = "https://github.com/DaZhi-the-Revelator/velvet";
+
+// Language server id of `v ls`, the server that ships with the V compiler,
+// as declared in extension.toml.
+const V_LS_SERVER: &str = "v-ls";
+
+// Checked-in initialization options, relative to the worktree root.  Layered
+// between the built-in defaults and the user's own settings.
+const PROJECT_INIT_OPTIONS_FILE: &str = ".velvet/zed-init.json";
+
+// Smallest accepted `server.max_memory_mb`; below this velvet cannot even
+// index the standard library.
+const MIN_SERVER_MEMORY_MB: u64 = 64;
+
+// Network requests are retried with exponential backoff so a single dropped
+// connection doesn't fail the whole operation.
+const NETWORK_ATTEMPTS: u32 = 3;
+const NETWORK_RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+// Debug adapter and task locator names, as declared in extension.toml.  V
+// compiles to C, so programs built with `v -g` are debugged with codelldb.
+const DEBUG_ADAPTER_NAME: &str = "V";
+const DEBUG_LOCATOR_NAME: &str = "v";
+
+// Context server serving V documentation to the assistant, as declared in
+// extension.toml.  Its source is written to the work directory and run with
+// `v run`, so it needs nothing beyond the V install it documents.
+const V_DOCS_SERVER: &str = "v-docs";
+const V_DOCS_SOURCE: &str = include_str!("../context_server/v_docs.v");
+const V_DOCS_SOURCE_PATH: &str = "v-docs/v_docs.v";
+
+// Longest `v doc` output `/v-doc` inserts.  Whole modules such as `os` run to
+// hundreds of kilobytes, which would crowd everything else out of the
+// assistant's context.
+const V_DOC_MAX_BYTES: usize = 32 * 1024;
+
 // --- Extension state ---------------------------------------------------------
 
 struct VEnhancedExtension {
-    cached_binary_path: Option<String>,
+    /// The velvet resolved for each worktree, by root path.  Worktrees can
+    /// differ (a project-local velvet, a `binary.path` in project settings),
+    /// so one worktree's answer is never reused for another.
+    cached_binary_paths: HashMap<String, String>,
     /// True once we have shown the update notification in this session so we
     /// don't spam the user every time a new language server is resolved.
     update_check_done: bool,
+    /// `VROOT` of the last worktree a language server was started for, so
+    /// `/v-doc` can complete module names from its `vlib`.
+    vroot: Option<String>,
+    /// `VEXE` of that worktree.  The `v-docs` context server has no worktree
+    /// to look `v` up in, so it uses this one.
+    vexe: Option<String>,
 }
 
 // --- zed::Extension impl -----------------------------------------------------
@@ -24,8 +79,10 @@ struct VEnhancedExtension {
 impl zed::Extension for VEnhancedExtension {
     fn new() -> Self {
         Self {
-            cached_binary_path: None,
+            cached_binary_paths: HashMap::new(),
             update_check_done: false,
+            vroot: None,
+            vexe: None,
         }
     }
 
@@ -36,26 +93,51 @@ impl zed::Extension for VEnhancedExtension {
         language_server_id: &LanguageServerId,
         worktree: &zed::Worktree,
     ) -> Result<zed::Command> {
+        if language_server_id.as_ref() == V_LS_SERVER {
+            let command = self.v_ls_command(worktree)?;
+            self.remember_toolchain(&command.env);
+            return Ok(command);
+        }
         let binary_path = self.velvet_binary_path(language_server_id, worktree)?;
 
-        // Run the update check once per session, after we have located the binary.
-        if !self.update_check_done {
+        // Run the update check once per session, after we have located the
+        // binary, unless lsp.velvet.settings.check_updates is false.
+        if !self.update_check_done && update_checks_enabled(worktree) {
             self.update_check_done = true;
             self.check_velvet_update(language_server_id, &binary_path);
         }
 
+        // Extra arguments from lsp.velvet.binary.arguments, e.g. for a
+        // development build that needs a flag to run as a server.
+        let args = zed::settings::LspSettings::for_worktree("velvet", worktree)
+            .ok()
+            .and_then(|lsp_settings| lsp_settings.binary)
+            .and_then(|binary| binary.arguments)
+            .unwrap_or_default();
+
+        let env = self.velvet_env(worktree)?;
+        self.remember_toolchain(&env);
+
         Ok(zed::Command {
             command: binary_path,
-            args: vec![],
-            env: Default::default(),
+            args,
+            env,
         })
     }
 
     fn language_server_initialization_options(
         &mut self,
-        _language_server_id: &LanguageServerId,
+        language_server_id: &LanguageServerId,
         worktree: &zed::Worktree,
     ) -> Result<Option<zed::serde_json::Value>> {
+        // `v ls` has its own option schema; it gets only what the user set.
+        if language_server_id.as_ref() == V_LS_SERVER {
+            let options = zed::settings::LspSettings::for_worktree(V_LS_SERVER, worktree)
+                .ok()
+                .and_then(|lsp_settings| lsp_settings.initialization_options);
+            return Ok(options);
+        }
+
         // Start with the built-in defaults.
         let mut options = zed::serde_json::json!({
             "inlay_hints": {
@@ -83,6 +165,21 @@ impl zed::Extension for VEnhancedExtension {
             }
         });
 
+        // Ask for messages in the configured language, if any.
+        if let Some(locale) = velvet_locale(worktree) {
+            options["locale"] = zed::serde_json::Value::String(locale_tag(&locale));
+        }
+
+        // Then the repository's shared options, if it has any.
+        if let Some(project_options) = self.project_init_options(worktree) {
+            merge_json(&mut options, project_options);
+        }
+
+        // Then the shorthands under lsp.velvet.settings.
+        if let Some(settings_options) = settings_init_options(worktree)? {
+            merge_json(&mut options, settings_options);
+        }
+
         // Merge any user-supplied initialization_options from settings.json on
         // top of the defaults.  This lets users override individual keys (e.g.
         // "inspections.enable_unused_parameter_warning") without having to
@@ -95,69 +192,759 @@ impl zed::Extension for VEnhancedExtension {
 
         Ok(Some(options))
     }
+
+    fn language_server_workspace_configuration(
+        &mut self,
+        language_server_id: &LanguageServerId,
+        worktree: &zed::Worktree,
+    ) -> Result<Option<zed::serde_json::Value>> {
+        // Answer velvet's `workspace/configuration` requests with
+        // lsp.velvet.settings (and `v ls`'s with lsp.v-ls.settings).  Zed
+        // asks again and notifies the server (`didChangeConfiguration`)
+        // whenever settings.json changes, so these take effect without a
+        // restart.
+        let settings = zed::settings::LspSettings::for_worktree(language_server_id.as_ref(), worktree)
+            .ok()
+            .and_then(|lsp_settings| lsp_settings.settings);
+        Ok(settings)
+    }
+
+    fn label_for_completion(
+        &self,
+        _language_server_id: &LanguageServerId,
+        completion: zed::lsp::Completion,
+    ) -> Option<zed::CodeLabel> {
+        completion_label(&completion)
+    }
+
+    fn label_for_symbol(
+        &self,
+        _language_server_id: &LanguageServerId,
+        symbol: zed::lsp::Symbol,
+    ) -> Option<zed::CodeLabel> {
+        symbol_label(&symbol)
+    }
+
+    // -- Slash commands -------------------------------------------------------
+
+    fn complete_slash_command_argument(
+        &self,
+        command: zed::SlashCommand,
+        args: Vec<String>,
+    ) -> Result<Vec<zed::SlashCommandArgumentCompletion>> {
+        if command.name != "v-doc" {
+            return Err(format!("unknown slash command: \"{}\"", command.name));
+        }
+        let Some(vroot) = &self.vroot else {
+            return Ok(Vec::new());
+        };
+        // `net.ht` completes among the submodules of `net`.
+        let typed = args.first().map(String::as_str).unwrap_or("");
+        let (parent, partial) = typed.rsplit_once('.').unwrap_or(("", typed));
+        let completions = vlib_modules(vroot, parent)
+            .into_iter()
+            .filter(|name| name.starts_with(partial))
+            .map(|name| {
+                let module = if parent.is_empty() { name } else { format!("{parent}.{name}") };
+                zed::SlashCommandArgumentCompletion {
+                    label: module.clone(),
+                    new_text: module,
+                    run_command: true,
+                }
+            })
+            .collect();
+        Ok(completions)
+    }
+
+    fn run_slash_command(
+        &self,
+        command: zed::SlashCommand,
+        args: Vec<String>,
+        worktree: Option<&zed::Worktree>,
+    ) -> Result<zed::SlashCommandOutput> {
+        if command.name != "v-doc" {
+            return Err(format!("unknown slash command: \"{}\"", command.name));
+        }
+        let Some(target) = args.first().map(|arg| arg.trim()).filter(|arg| !arg.is_empty()) else {
+            return Err("usage: /v-doc <module or symbol>, e.g. /v-doc os.read_file".to_string());
+        };
+        let worktree = worktree.ok_or("/v-doc needs an open project to find the V compiler")?;
+        let v = worktree.which("v").ok_or_else(|| {
+            "`v` not found in PATH.\n\n\
+             /v-doc runs `v doc` from your V installation. Install V from\n\
+             https://github.com/vlang/v and make sure `v` is on your PATH."
+                .to_string()
+        })?;
+        let env = toolchain_env(worktree);
+
+        // `net.http` is a module; `os.read_file` is `v doc os read_file`.
+        let mut docs = v_doc(&v, &env, &[target]);
+        if docs.is_err() {
+            if let Some((module, symbol)) = target.rsplit_once('.') {
+                docs = v_doc(&v, &env, &[module, symbol]).or(docs);
+            }
+        }
+        let docs = truncate_docs(docs?.trim_end(), V_DOC_MAX_BYTES);
+
+        let text = format!("```\n{docs}\n```\n");
+        Ok(zed::SlashCommandOutput {
+            sections: vec![zed::SlashCommandOutputSection {
+                range: (0..text.len()).into(),
+                label: format!("v doc {target}"),
+            }],
+            text,
+        })
+    }
+
+    // -- Debugging ------------------------------------------------------------
+
+    fn get_dap_binary(
+        &mut self,
+        adapter_name: String,
+        definition: zed::DebugTaskDefinition,
+        user_provided_debug_adapter_path: Option<String>,
+        worktree: &zed::Worktree,
+    ) -> Result<zed::DebugAdapterBinary> {
+        if adapter_name != DEBUG_ADAPTER_NAME {
+            return Err(format!("unknown debug adapter: {adapter_name}"));
+        }
+        let config: zed::serde_json::Value = zed::serde_json::from_str(&definition.config)
+            .map_err(|e| format!("invalid V debug configuration: {e}"))?;
+        if config["request"] != "launch" {
+            return Err("V debug configurations support only \"request\": \"launch\"; \
+                        attaching to a running program is not supported yet."
+                .to_string());
+        }
+        let program = config["program"]
+            .as_str()
+            .filter(|p| !p.is_empty())
+            .ok_or("V debug configuration has no \"program\" to debug")?;
+
+        let windows = zed::current_platform().0 == zed::Os::Windows;
+        let command = user_provided_debug_adapter_path
+            .or_else(|| worktree.which(if windows { "codelldb.exe" } else { "codelldb" }))
+            .ok_or_else(|| {
+                "codelldb not found in PATH.\n\n\
+                 V programs are debugged with codelldb. Install it from\n\
+                 https://github.com/vadimcn/codelldb/releases and put the\n\
+                 extension/adapter directory on your PATH, or set\n\
+                 dap.V.binary in your Zed settings.json."
+                    .to_string()
+            })?;
+
+        // codelldb talks DAP over stdio unless told to listen on a port.
+        let (arguments, connection) = match definition.tcp_connection {
+            Some(template) => {
+                let tcp = zed::resolve_tcp_template(template)?;
+                (vec!["--port".to_string(), tcp.port.to_string()], Some(tcp))
+            }
+            None => (Vec::new(), None),
+        };
+
+        let launch = zed::serde_json::json!({
+            "request": "launch",
+            "program": program,
+            "args": non_null(&config["args"], zed::serde_json::json!([])),
+            "cwd": non_null(&config["cwd"], worktree.root_path().into()),
+            "env": non_null(&config["env"], zed::serde_json::json!({})),
+            "stopOnEntry": config["stopOnEntry"].as_bool().unwrap_or(false),
+        });
+
+        Ok(zed::DebugAdapterBinary {
+            command: Some(command),
+            arguments,
+            envs: Vec::new(),
+            cwd: Some(worktree.root_path()),
+            connection,
+            request_args: zed::StartDebuggingRequestArguments {
+                configuration: launch.to_string(),
+                request: zed::StartDebuggingRequestArgumentsRequest::Launch,
+            },
+        })
+    }
+
+    fn dap_request_kind(
+        &mut self,
+        _adapter_name: String,
+        config: zed::serde_json::Value,
+    ) -> Result<zed::StartDebuggingRequestArgumentsRequest> {
+        match config["request"].as_str() {
+            Some("launch") => Ok(zed::StartDebuggingRequestArgumentsRequest::Launch),
+            Some("attach") => Ok(zed::StartDebuggingRequestArgumentsRequest::Attach),
+            _ => Err("V debug configuration needs \"request\": \"launch\"".to_string()),
+        }
+    }
+
+    fn dap_config_to_scenario(&mut self, config: zed::DebugConfig) -> Result<zed::DebugScenario> {
+        let zed::DebugRequest::Launch(launch) = config.request else {
+            return Err("attaching to a running V program is not supported yet".to_string());
+        };
+
+        // A .v file is built with debug info first and its binary debugged.
+        let windows = zed::current_platform().0 == zed::Os::Windows;
+        let is_source = launch.program.ends_with(".v") || launch.program.ends_with(".vsh");
+        let (program, build) = if is_source {
+            let program = v_output_path(&launch.program, launch.cwd.as_deref(), windows);
+            let build = zed::BuildTaskDefinition::Template(zed::BuildTaskDefinitionTemplatePayload {
+                locator_name: None,
+                template: zed::TaskTemplate {
+                    label: format!("v -g {}", launch.program),
+                    command: "v".to_string(),
+                    args: vec!["-g".to_string(), "-o".to_string(), program.clone(), launch.program.clone()],
+                    env: launch.envs.clone(),
+                    cwd: launch.cwd.clone(),
+                },
+            });
+            (program, Some(build))
+        } else {
+            (launch.program, None)
+        };
+
+        let env: zed::serde_json::Map<String, zed::serde_json::Value> = launch
+            .envs
+            .into_iter()
+            .map(|(key, value)| (key, value.into()))
+            .collect();
+        let debug_config = zed::serde_json::json!({
+            "request": "launch",
+            "program": program,
+            "args": launch.args,
+            "cwd": launch.cwd,
+            "env": env,
+            "stopOnEntry": config.stop_on_entry.unwrap_or(false),
+        });
+
+        Ok(zed::DebugScenario {
+            label: config.label,
+            adapter: config.adapter,
+            build,
+            config: debug_config.to_string(),
+            tcp_connection: None,
+        })
+    }
+
+    fn dap_locator_create_scenario(
+        &mut self,
+        locator_name: String,
+        build_task: zed::TaskTemplate,
+        resolved_label: String,
+        debug_adapter_name: String,
+    ) -> Option<zed::DebugScenario> {
+        if locator_name != DEBUG_LOCATOR_NAME || build_task.command != "v" {
+            return None;
+        }
+        // `v [flags] run <target>` becomes `v [flags] -g <target>`; once it
+        // has built, `run_dap_locator` points the debugger at the binary.
+        let run = build_task.args.iter().position(|arg| arg == "run")?;
+        let target = build_task.args.get(run + 1)?.clone();
+        let mut args = build_task.args[..run].to_vec();
+        args.extend(["-g".to_string(), target.clone()]);
+
+        Some(zed::DebugScenario {
+            label: resolved_label,
+            adapter: debug_adapter_name,
+            build: Some(zed::BuildTaskDefinition::Template(zed::BuildTaskDefinitionTemplatePayload {
+                locator_name: Some(DEBUG_LOCATOR_NAME.to_string()),
+                template: zed::TaskTemplate {
+                    label: format!("v -g {target}"),
+                    command: build_task.command,
+                    args,
+                    env: build_task.env,
+                    cwd: build_task.cwd,
+                },
+            })),
+            config: "null".to_string(),
+            tcp_connection: None,
+        })
+    }
+
+    fn run_dap_locator(
+        &mut self,
+        _locator_name: String,
+        build_task: zed::TaskTemplate,
+    ) -> Result<zed::DebugRequest> {
+        let target = build_task
+            .args
+            .last()
+            .ok_or("the V debug build task has no target")?;
+        let windows = zed::current_platform().0 == zed::Os::Windows;
+        Ok(zed::DebugRequest::Launch(zed::LaunchRequest {
+            program: v_output_path(target, build_task.cwd.as_deref(), windows),
+            cwd: build_task.cwd,
+            args: Vec::new(),
+            envs: build_task.env,
+        }))
+    }
+
+    // -- Context servers ------------------------------------------------------
+
+    fn context_server_command(
+        &mut self,
+        context_server_id: &zed::ContextServerId,
+        project: &zed::Project,
+    ) -> Result<zed::Command> {
+        if context_server_id.as_ref() != V_DOCS_SERVER {
+            return Err(format!("unknown context server: \"{}\"", context_server_id.as_ref()));
+        }
+        // `v` from context_servers.v-docs.command.path, else the one the
+        // language servers use, else whatever `v` is on PATH.
+        let command = zed::settings::ContextServerSettings::for_project(V_DOCS_SERVER, project)
+            .ok()
+            .and_then(|settings| settings.command);
+        let v = command
+            .as_ref()
+            .and_then(|c| c.path.clone())
+            .or_else(|| self.vexe.clone())
+            .unwrap_or_else(|| "v".to_string());
+
+        // Without V the server cannot even start, so say why here instead
+        // of leaving the assistant with a server that never answers.
+        let runs = zed::process::Command::new(&v)
+            .arg("version")
+            .output()
+            .is_ok_and(|output| output.status == Some(0));
+        if !runs {
+            return Err(format!(
+                "cannot run `{v} version`.\n\n\
+                 The v-docs context server runs on your V installation. Install V from\n\
+                 https://github.com/vlang/v and make sure `v` is on your PATH, or set\n\
+                 context_servers.v-docs.command.path in your Zed settings.json."
+            ));
+        }
+
+        // Rewritten every time, so the server matches this extension version.
+        std::fs::create_dir_all("v-docs").map_err(|e| format!("cannot create v-docs: {e}"))?;
+        std::fs::write(V_DOCS_SOURCE_PATH, V_DOCS_SOURCE)
+            .map_err(|e| format!("cannot write {V_DOCS_SOURCE_PATH}: {e}"))?;
+        let work_dir = std::env::current_dir().map_err(|e| format!("cannot find the work directory: {e}"))?;
+        let source = work_dir.join(V_DOCS_SOURCE_PATH).to_string_lossy().into_owned();
+
+        let mut env: Vec<(String, String)> = self.vroot.iter().map(|vroot| ("VROOT".to_string(), vroot.clone())).collect();
+        if let Some(extra) = command.and_then(|c| c.env) {
+            for (key, value) in extra {
+                set_env(&mut env, key, value);
+            }
+        }
+        Ok(zed::Command {
+            command: v,
+            args: vec!["run".to_string(), source],
+            env,
+        })
+    }
 }
 
 // --- LSP helper methods ------------------------------------------------------
 
 impl VEnhancedExtension {
+    /// Remember the toolchain a language server was started with, for
+    /// `/v-doc` completions and the `v-docs` context server.
+    fn remember_toolchain(&mut self, env: &[(String, String)]) {
+        let var = |name: &str| env.iter().find(|(key, _)| key == name).map(|(_, value)| value.clone());
+        self.vroot = var("VROOT");
+        self.vexe = var("VEXE");
+    }
+
+    /// Read `PROJECT_INIT_OPTIONS_FILE` from the worktree.  A missing file is
+    /// normal; a malformed one is reported and ignored so it cannot keep the
+    /// language server from starting.
+    fn project_init_options(&self, worktree: &zed::Worktree) -> Option<zed::serde_json::Value> {
+        let text = worktree.read_text_file(PROJECT_INIT_OPTIONS_FILE).ok()?;
+        match zed::serde_json::from_str::<zed::serde_json::Value>(&text) {
+            Ok(value) if value.is_object() => Some(value),
+            Ok(_) => {
+                eprintln!("[v-enhanced] ignoring {PROJECT_INIT_OPTIONS_FILE}: expected a JSON object");
+                None
+            }
+            Err(e) => {
+                eprintln!("[v-enhanced] ignoring {PROJECT_INIT_OPTIONS_FILE}: {e}");
+                None
+            }
+        }
+    }
+
     fn velvet_binary_path(
         &mut self,
         language_server_id: &LanguageServerId,
         worktree: &zed::Worktree,
     ) -> Result<String> {
-        if let Some(path) = &self.cached_binary_path {
-            if std::fs::metadata(path).is_ok() {
+        let root = worktree.root_path();
+        if let Some(path) = self.cached_binary_paths.get(&root) {
+            let windows = zed::current_platform().0 == zed::Os::Windows;
+            if velvet_exists(path, windows) {
                 return Ok(path.clone());
             }
+            // Uninstalled or replaced since: resolve it again from scratch.
+            self.cached_binary_paths.remove(&root);
         }
 
+        // Zed shows this status while velvet is being resolved:
+        // CheckingForUpdate, then Downloading while `download_velvet`
+        // fetches a release, then None, or Failed with the reason, so a
+        // failure is visible without opening the log.
         zed::set_language_server_installation_status(
             language_server_id,
             &zed::LanguageServerInstallationStatus::CheckingForUpdate,
         );
+        let resolved = self.resolve_velvet(language_server_id, worktree);
+        let status = match &resolved {
+            Ok(_) => zed::LanguageServerInstallationStatus::None,
+            Err(e) => zed::LanguageServerInstallationStatus::Failed(e.clone()),
+        };
+        zed::set_language_server_installation_status(language_server_id, &status);
+
+        let path = resolved?;
+        self.cached_binary_paths.insert(root, path.clone());
+        Ok(path)
+    }
 
+    fn resolve_velvet(&self, language_server_id: &LanguageServerId, worktree: &zed::Worktree) -> Result<String> {
         // Check lsp.velvet.binary.path from Zed settings.json first.
         // This lets users point at a non-PATH install without needing to
         // add it to their shell PATH.
-        let path = if let Ok(lsp_settings) = zed::settings::LspSettings::for_worktree("velvet", worktree) {
-            if let Some(binary) = lsp_settings.binary {
-                if let Some(configured_path) = binary.path {
-                    if std::fs::metadata(&configured_path).is_ok() {
-                        configured_path
-                    } else {
-                        return Err(format!(
-                            "velvet binary not found at configured path: {configured_path}\n\
-                             Check lsp.velvet.binary.path in your Zed settings.json."
-                        ));
-                    }
-                } else {
-                    self.find_velvet_in_path(worktree)?
+        // A relative path is taken from the worktree root, so a project can
+        // point at a velvet it vendors.
+        if let Ok(lsp_settings) = zed::settings::LspSettings::for_worktree("velvet", worktree) {
+            if let Some(configured_path) = lsp_settings.binary.and_then(|binary| binary.path) {
+                let configured_path = worktree_path(worktree, &configured_path);
+                let windows = zed::current_platform().0 == zed::Os::Windows;
+                if host_file_exists(&configured_path, windows) {
+                    return Ok(configured_path);
                 }
-            } else {
-                self.find_velvet_in_path(worktree)?
+                return Err(format!(
+                    "velvet binary not found at configured path: {configured_path}\n\
+                     Check lsp.velvet.binary.path in your Zed settings.json."
+                ));
             }
-        } else {
-            self.find_velvet_in_path(worktree)?
+        }
+        self.find_or_download_velvet(language_server_id, worktree)
+    }
+
+    /// Environment for the velvet process.
+    ///
+    /// velvet needs the V toolchain to index the standard library.  `VEXE`,
+    /// `VROOT` and `VMODULES` are taken from the shell, with `VEXE` and
+    /// `VROOT` otherwise derived from the `v` on PATH.  Pairs under
+    /// `lsp.velvet.binary.env` are added last and win.
+    ///
+    /// `server.max_memory_mb` in `lsp.velvet.settings` caps velvet's heap.
+    /// velvet is a V program using the Boehm GC, which honours
+    /// `GC_MAXIMUM_HEAP_SIZE` (in bytes).  `locale` sets `LC_MESSAGES`.
+    fn velvet_env(&self, worktree: &zed::Worktree) -> Result<Vec<(String, String)>> {
+        let mut env = toolchain_env(worktree);
+        if let Some(locale) = velvet_locale(worktree) {
+            env.push(("LC_MESSAGES".to_string(), locale.replace('-', "_")));
+        }
+
+        let lsp_settings = zed::settings::LspSettings::for_worktree("velvet", worktree).ok();
+        let settings = lsp_settings.as_ref().and_then(|s| s.settings.as_ref());
+        let max_memory = settings.map_or(&zed::serde_json::Value::Null, |s| &s["server"]["max_memory_mb"]);
+        if !max_memory.is_null() {
+            let mb = max_memory
+                .as_u64()
+                .filter(|mb| *mb >= MIN_SERVER_MEMORY_MB)
+                .ok_or_else(|| {
+                    format!(
+                        "Invalid lsp.velvet.settings.server.max_memory_mb: {max_memory}. \
+                         Expected a whole number of megabytes, at least {MIN_SERVER_MEMORY_MB}."
+                    )
+                })?;
+            env.push((
+                "GC_MAXIMUM_HEAP_SIZE".to_string(),
+                (mb * 1024 * 1024).to_string(),
+            ));
+        }
+
+        if let Some(extra) = lsp_settings.and_then(|s| s.binary).and_then(|binary| binary.env) {
+            for (key, value) in extra {
+                set_env(&mut env, key, value);
+            }
+        }
+
+        Ok(env)
+    }
+
+    /// The command for `v ls`: `v` from PATH (or `lsp.v-ls.binary.path`)
+    /// with `ls` and `lsp.v-ls.binary.arguments`.
+    ///
+    /// Zed starts every language server registered for V, so `v ls` only
+    /// runs as a fallback when no velvet can be found, unless
+    /// `lsp.v-ls.settings.enable` is `true`.
+    fn v_ls_command(&self, worktree: &zed::Worktree) -> Result<zed::Command> {
+        let lsp_settings = zed::settings::LspSettings::for_worktree(V_LS_SERVER, worktree).ok();
+        let enabled = lsp_settings
+            .as_ref()
+            .and_then(|s| s.settings.as_ref())
+            .and_then(|settings| settings["enable"].as_bool())
+            .unwrap_or(false);
+        if !enabled && self.velvet_available(worktree) {
+            return Err("v ls is not started because velvet is in use.\n\n\
+                        To use v ls instead, set lsp.v-ls.settings.enable to true and add\n\
+                        \"!velvet\" to languages.V.language_servers in your Zed settings.json."
+                .to_string());
+        }
+
+        let binary = lsp_settings.and_then(|s| s.binary);
+        let v = match binary.as_ref().and_then(|b| b.path.clone()) {
+            Some(path) => path,
+            None => worktree.which("v").ok_or_else(|| {
+                "v not found in PATH.\n\n\
+                 v ls ships with the V compiler. Install V from\n\
+                 https://github.com/vlang/v and make sure `v` is on your PATH, or set\n\
+                 lsp.v-ls.binary.path in your Zed settings.json."
+                    .to_string()
+            })?,
+        };
+        let mut args = vec!["ls".to_string()];
+        args.extend(binary.as_ref().and_then(|b| b.arguments.clone()).unwrap_or_default());
+
+        let mut env = toolchain_env(worktree);
+        if let Some(extra) = binary.and_then(|b| b.env) {
+            for (key, value) in extra {
+                set_env(&mut env, key, value);
+            }
+        }
+        Ok(zed::Command { command: v, args, env })
+    }
+
+    /// Whether velvet can be started without downloading it: resolved
+    /// before, configured, on PATH, in an install directory, or downloaded
+    /// or built in an earlier session.
+    fn velvet_available(&self, worktree: &zed::Worktree) -> bool {
+        let windows = zed::current_platform().0 == zed::Os::Windows;
+        let cached = self.cached_binary_paths.get(&worktree.root_path());
+        if cached.is_some_and(|path| velvet_exists(path, windows)) {
+            return true;
+        }
+        let configured = zed::settings::LspSettings::for_worktree("velvet", worktree)
+            .ok()
+            .and_then(|s| s.binary)
+            .and_then(|binary| binary.path);
+        if let Some(path) = configured {
+            return host_file_exists(&worktree_path(worktree, &path), windows);
+        }
+        let built = ["velvet", "velvet.exe"]
+            .iter()
+            .any(|name| std::fs::metadata(format!("{VELVET_SOURCE_DIR}/bin/{name}")).is_ok());
+        self.find_velvet_in_path(worktree).is_ok() || downloaded_velvet().is_some() || built
+    }
+
+    /// A velvet on PATH wins; otherwise fetch the latest prebuilt release,
+    /// and failing that build it from source if the user opted in.  Only
+    /// when all of these fail is the user asked to install velvet by hand.
+    fn find_or_download_velvet(
+        &self,
+        language_server_id: &LanguageServerId,
+        worktree: &zed::Worktree,
+    ) -> Result<String> {
+        let path_err = match self.find_velvet_in_path(worktree) {
+            Ok(path) => return Ok(path),
+            Err(e) => e,
         };
+        let download = self.download_velvet(
+            language_server_id,
+            update_checks_enabled(worktree),
+            unverified_download_allowed(worktree),
+        );
+        let download_err = match download {
+            Ok(path) => return Ok(path),
+            Err(e) => e,
+        };
+        eprintln!("[v-enhanced] velvet download failed: {download_err}");
+        let err = format!("{path_err}\n\nDownloading a prebuilt velvet also failed: {download_err}");
+        if !build_from_source_enabled(worktree) {
+            return Err(format!(
+                "{err}\n\nTo have the extension build velvet with your V compiler instead, set\n\
+                 lsp.velvet.settings.build_from_source to true in your Zed settings.json."
+            ));
+        }
+        self.build_velvet(language_server_id, worktree).map_err(|build_err| {
+            eprintln!("[v-enhanced] velvet source build failed: {build_err}");
+            format!("{err}\n\nBuilding velvet from source also failed: {build_err}")
+        })
+    }
+
+    /// Clone velvet into `VELVET_SOURCE_DIR` and build it with the
+    /// worktree's `v`, as `v run build.vsh release`.  A binary built in an
+    /// earlier session is reused; a checkout left by a failed build is
+    /// updated and built again.
+    fn build_velvet(&self, language_server_id: &LanguageServerId, worktree: &zed::Worktree) -> Result<String> {
+        let (os, _) = zed::current_platform();
+        let binary_name = if os == zed::Os::Windows { "velvet.exe" } else { "velvet" };
+        let binary_path = format!("{VELVET_SOURCE_DIR}/bin/{binary_name}");
+        if std::fs::metadata(&binary_path).is_ok_and(|m| m.is_file()) {
+            return Ok(binary_path);
+        }
+
+        let git = worktree
+            .which("git")
+            .ok_or("git not found in PATH; it is needed to fetch the velvet sources.")?;
+        let v = worktree
+            .which("v")
+            .ok_or("v not found in PATH; it is needed to build velvet.")?;
+        // git and v run outside the work directory, so give them full paths.
+        let work_dir = std::env::current_dir().map_err(|e| format!("cannot find the work directory: {e}"))?;
+        let source_dir = work_dir.join(VELVET_SOURCE_DIR).to_string_lossy().into_owned();
 
         zed::set_language_server_installation_status(
             language_server_id,
-            &zed::LanguageServerInstallationStatus::None,
+            &zed::LanguageServerInstallationStatus::Downloading,
         );
+        if std::fs::metadata(format!("{VELVET_SOURCE_DIR}/.git")).is_ok() {
+            eprintln!("[v-enhanced] updating velvet sources in {source_dir}");
+            run_tool(&git, &["-C", &source_dir, "pull", "--ff-only", "--recurse-submodules"], &[])?;
+        } else {
+            eprintln!("[v-enhanced] cloning {VELVET_GIT_URL} into {source_dir}");
+            std::fs::remove_dir_all(VELVET_SOURCE_DIR).ok();
+            run_tool(
+                &git,
+                &["clone", "--depth", "1", "--recurse-submodules", VELVET_GIT_URL, &source_dir],
+                &[],
+            )?;
+        }
 
-        self.cached_binary_path = Some(path.clone());
-        Ok(path)
+        // Zed has no "building" status; checking is the closest.
+        zed::set_language_server_installation_status(
+            language_server_id,
+            &zed::LanguageServerInstallationStatus::CheckingForUpdate,
+        );
+        eprintln!("[v-enhanced] building velvet with {v}; this takes a few minutes");
+        let build_script = format!("{source_dir}/build.vsh");
+        run_tool(&v, &["run", &build_script, "release"], &toolchain_env(worktree))?;
+        if std::fs::metadata(&binary_path).is_err() {
+            return Err(format!("the build finished but {binary_path} was not produced"));
+        }
+        zed::make_file_executable(&binary_path)?;
+        Ok(binary_path)
     }
 
-    fn find_velvet_in_path(&self, worktree: &zed::Worktree) -> Result<String> {
-        let binary_name = if cfg!(target_os = "windows") {
-            "velvet.exe"
-        } else {
-            "velvet"
+    /// Download the latest velvet release for this platform into the
+    /// extension's work directory, as `velvet-<version>/velvet`.  A version
+    /// already downloaded is reused; older versions are removed.  Without
+    /// `check_updates`, a velvet downloaded earlier is used as is, without
+    /// asking GitHub for a newer one.
+    fn download_velvet(
+        &self,
+        language_server_id: &LanguageServerId,
+        check_updates: bool,
+        allow_unverified: bool,
+    ) -> Result<String> {
+        if !check_updates {
+            if let Some(path) = downloaded_velvet() {
+                return Ok(path);
+            }
+        }
+        let release = match zed::latest_github_release(
+            VELVET_REPO,
+            zed::GithubReleaseOptions {
+                require_assets: true,
+                pre_release: false,
+            },
+        ) {
+            Ok(release) => release,
+            // Offline, or GitHub is unreachable: a velvet downloaded earlier
+            // still works, so only fail when there is none.
+            Err(e) => match downloaded_velvet() {
+                Some(path) => {
+                    eprintln!("[v-enhanced] could not check for a velvet update ({e}); using {path}");
+                    return Ok(path);
+                }
+                None => return Err(e),
+            },
+        };
+
+        // Assets are named `velvet-<os>-<arch>`: a .zip on Windows and a
+        // .tar.gz elsewhere, though either format is accepted.
+        let (os, arch) = zed::current_platform();
+        let (os_name, formats) = match os {
+            zed::Os::Mac => ("darwin", ["tar.gz", "zip"]),
+            zed::Os::Linux => ("linux", ["tar.gz", "zip"]),
+            zed::Os::Windows => ("windows", ["zip", "tar.gz"]),
+        };
+        let arch_name = match arch {
+            zed::Architecture::Aarch64 => "arm64",
+            zed::Architecture::X8664 => "x86_64",
+            zed::Architecture::X86 => "x86",
         };
+        let platform = format!("{os_name}-{arch_name}");
+        let asset = formats
+            .iter()
+            .find_map(|format| {
+                let name = format!("velvet-{platform}.{format}");
+                release.assets.iter().find(|asset| asset.name == name)
+            })
+            .ok_or_else(|| {
+                format!(
+                    "velvet {} has no prebuilt binary for {platform} (available: {}).\n\n\
+                     Build velvet from source instead:\n\
+                     git clone --recursive https://github.com/DaZhi-the-Revelator/velvet\n\
+                     cd velvet && v run build.vsh release\n\n\
+                     Then copy bin/velvet to your PATH, or set lsp.velvet.binary.path in\n\
+                     your Zed settings.json.",
+                    release.version,
+                    release.assets.iter().map(|a| a.name.as_str()).collect::<Vec<_>>().join(", ")
+                )
+            })?;
+        let asset_name = &asset.name;
+
+        let version_dir = format!("velvet-{}", release.version);
+        let binary_name = if os == zed::Os::Windows { "velvet.exe" } else { "velvet" };
+        let binary_path = format!("{version_dir}/{binary_name}");
+
+        if std::fs::metadata(&binary_path).is_err() {
+            // Without a checksum the download cannot be verified, so it is
+            // not installed unless the user opted in.
+            let checksum = release.assets.iter().find(|a| a.name == format!("{asset_name}.sha256"));
+            if checksum.is_none() && !allow_unverified {
+                return Err(format!(
+                    "velvet {} publishes no {asset_name}.sha256, so the download cannot be verified                      and was not installed.
+                     To install it anyway, set lsp.velvet.settings.allow_unverified_download to true
+                     in your Zed settings.json.",
+                    release.version
+                ));
+            }
+            zed::set_language_server_installation_status(
+                language_server_id,
+                &zed::LanguageServerInstallationStatus::Downloading,
+            );
+            eprintln!("[v-enhanced] downloading {asset_name} from velvet {}", release.version);
+            // Anything left from a failed attempt goes, so a retry starts clean.
+            let installed = install_velvet(asset, checksum, &version_dir, binary_name);
+            if installed.is_err() {
+                std::fs::remove_dir_all(&version_dir).ok();
+            }
+            installed?;
+
+            // Only the version in use is kept.
+            if let Ok(entries) = std::fs::read_dir(".") {
+                for entry in entries.flatten() {
+                    let name = entry.file_name();
+                    let name = name.to_string_lossy();
+                    if name.starts_with("velvet-") && name != version_dir {
+                        std::fs::remove_dir_all(entry.path()).ok();
+                    }
+                }
+            }
+        }
+
+        Ok(binary_path)
+    }
 
-        worktree.which(binary_name).ok_or_else(|| {
-            "velvet not found in PATH.\n\n\
+    fn find_velvet_in_path(&self, worktree: &zed::Worktree) -> Result<String> {
+        // The extension itself is WebAssembly, so ask Zed which OS this is.
+        let windows = zed::current_platform().0 == zed::Os::Windows;
+        let binary_name = if windows { "velvet.exe" } else { "velvet" };
+
+        // A velvet the project vendors comes first, so everyone working on it
+        // gets the same diagnostics whatever they have installed.
+        let found = find_velvet_in_project(worktree, binary_name, windows)
+            .or_else(|| worktree.which(binary_name))
+            .or_else(|| find_velvet_in_install_dirs(worktree, binary_name, windows));
+        found.ok_or_else(|| {
+            "velvet not found.\n\n\
+             Looked for it, in order, in:\n\
+             - bin/, tools/bin/ and .bin/ in the project\n\
+             - PATH\n\
+             - ~/.config/velvet/bin, $VMODULES/bin and ~/.vmodules/bin\n\n\
              Please install velvet:\n\
              git clone --recursive https://github.com/DaZhi-the-Revelator/velvet\n\
              cd velvet && v run build.vsh release\n\n\
@@ -184,7 +971,16 @@ impl VEnhancedExtension {
         };
 
         // 2. Fetch the latest release tag name from GitHub.
-        let remote_version = match self.fetch_remote_release_tag() {
+        zed::set_language_server_installation_status(
+            language_server_id,
+            &zed::LanguageServerInstallationStatus::CheckingForUpdate,
+        );
+        let remote_version = self.fetch_remote_release_tag();
+        zed::set_language_server_installation_status(
+            language_server_id,
+            &zed::LanguageServerInstallationStatus::None,
+        );
+        let remote_version = match remote_version {
             Some(v) => v,
             None => return,
         };
@@ -193,28 +989,35 @@ impl VEnhancedExtension {
         let remote_clean = remote_version.trim_start_matches('v');
         let local_clean = local_version.trim_start_matches('v');
 
-        if local_clean != remote_clean {
-            zed::set_language_server_installation_status(
-                language_server_id,
-                &zed::LanguageServerInstallationStatus::Failed(
-                    format!(
-                        "velvet is out of date (local: {local_clean}, latest release: {remote_clean}). \
-                         Run: cd velvet && git pull && v run build.vsh release, \
-                         then copy bin/velvet to your PATH and restart Zed."
-                    ),
-                ),
-            );
+        if !is_older_version(local_clean, remote_clean) {
+            return;
         }
+        eprintln!("[v-enhanced] velvet {local_clean} is installed; {remote_clean} is available");
+        zed::set_language_server_installation_status(
+            language_server_id,
+            &zed::LanguageServerInstallationStatus::Failed(
+                format!(
+                    "velvet is out of date (local: {local_clean}, latest release: {remote_clean}). \
+                     Run: cd velvet && git pull && v run build.vsh release, \
+                     then copy bin/velvet to your PATH and restart Zed."
+                ),
+            ),
+        );
     }
 
     /// Run `velvet --version` and extract the version string.
     /// Handles output like "velvet version 0.1.0" or "velvet 0.1.0".
     /// Returns `None` if the binary cannot be run or the output is not parseable.
+    ///
+    /// The binary runs on the host, so a velvet downloaded or built into the
+    /// work directory is named by its full path there.
     fn get_local_version(&self, binary_path: &str) -> Option<String> {
-        let output = std::process::Command::new(binary_path)
-            .arg("--version")
-            .output()
-            .ok()?;
+        let binary_path = if is_absolute(binary_path) {
+            binary_path.to_string()
+        } else {
+            std::env::current_dir().ok()?.join(binary_path).to_string_lossy().into_owned()
+        };
+        let output = zed::process::Command::new(binary_path).arg("--version").output().ok()?;
 
         let text = String::from_utf8_lossy(&output.stdout).to_string();
         for line in text.lines() {
@@ -244,7 +1047,7 @@ impl VEnhancedExtension {
             .build()
             .ok()?;
 
-        let response = zed::http_client::fetch(&request).ok()?;
+        let response = with_retry("velvet release check", || zed::http_client::fetch(&request)).ok()?;
         let body = String::from_utf8(response.body).ok()?;
         let value: zed::serde_json::Value = zed::serde_json::from_str(&body).ok()?;
         // The releases/latest response has a "tag_name" field, e.g. "v0.1.0"
@@ -255,17 +1058,500 @@ impl VEnhancedExtension {
 
 // --- Helpers -----------------------------------------------------------------
 
+/// Run `op` up to `NETWORK_ATTEMPTS` times, doubling the delay between
+/// attempts.  Errors that retrying cannot fix (see `is_retryable`) are
+/// returned immediately.
+fn with_retry<T>(what: &str, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut delay = NETWORK_RETRY_BASE_DELAY;
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < NETWORK_ATTEMPTS && is_retryable(&err) => {
+                eprintln!(
+                    "[v-enhanced] debug: {what} failed (attempt {attempt}/{NETWORK_ATTEMPTS}): {err}; \
+                     retrying in {}ms",
+                    delay.as_millis()
+                );
+                std::thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Whether a failed request is worth retrying.  Missing resources and
+/// authorization failures are permanent; anything else (timeouts, connection
+/// resets, 5xx responses) is treated as transient.
+fn is_retryable(err: &str) -> bool {
+    let err = err.to_ascii_lowercase();
+    !["404", "not found", "401", "unauthorized", "403", "forbidden"]
+        .iter()
+        .any(|fatal| err.contains(fatal))
+}
+
+/// Download `asset` into `version_dir`, check it against the release's
+/// `.sha256` file and unpack `binary_name` from it.  The archive is fetched
+/// uncompressed so that the bytes checked are the bytes installed.
+fn install_velvet(
+    asset: &zed::GithubReleaseAsset,
+    checksum: Option<&zed::GithubReleaseAsset>,
+    version_dir: &str,
+    binary_name: &str,
+) -> Result<()> {
+    std::fs::create_dir_all(version_dir).map_err(|e| format!("cannot create {version_dir}: {e}"))?;
+    let archive_path = format!("{version_dir}/{}", asset.name);
+    with_retry("velvet download", || {
+        zed::download_file(&asset.download_url, &archive_path, zed::DownloadedFileType::Uncompressed)
+    })?;
+    let archive = std::fs::read(&archive_path).map_err(|e| format!("cannot read {archive_path}: {e}"))?;
+
+    match checksum {
+        Some(checksum) => {
+            let checksum_path = format!("{archive_path}.sha256");
+            with_retry("velvet checksum download", || {
+                zed::download_file(&checksum.download_url, &checksum_path, zed::DownloadedFileType::Uncompressed)
+            })?;
+            let listed = std::fs::read_to_string(&checksum_path)
+                .map_err(|e| format!("cannot read {checksum_path}: {e}"))?;
+            // `sha256sum` format: the digest, then optionally the file name.
+            let expected = listed.split_whitespace().next().unwrap_or("").to_ascii_lowercase();
+            let actual = sha256_hex(&archive);
+            if actual != expected {
+                return Err(format!(
+                    "downloaded {} is corrupt: its SHA-256 is {actual}, but {} lists {expected}. \
+                     The download was deleted; restart the language server to try again.",
+                    asset.name, checksum.name
+                ));
+            }
+        }
+        // Only reached with `allow_unverified_download`.
+        None => eprintln!("[v-enhanced] {} has no .sha256 file; installing it unverified", asset.name),
+    }
+
+    let binary = if asset.name.ends_with(".tar.gz") {
+        extract_from_tar_gz(&archive, binary_name)
+    } else {
+        extract_from_zip(&archive, binary_name)
+    }
+    .map_err(|e| format!("cannot unpack {}: {e}", asset.name))?;
+    let binary_path = format!("{version_dir}/{binary_name}");
+    std::fs::write(&binary_path, binary).map_err(|e| format!("cannot write {binary_path}: {e}"))?;
+    zed::make_file_executable(&binary_path)?;
+    std::fs::remove_file(&archive_path).ok();
+    Ok(())
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(bytes).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Read the file called `name` (in any directory) out of a zip archive.
+/// Only what release archives use is supported: stored or deflated entries,
+/// no zip64.
+fn extract_from_zip(archive: &[u8], name: &str) -> Result<Vec<u8>> {
+    let u16_at = |at: usize| archive.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as usize);
+    let u32_at = |at: usize| {
+        archive
+            .get(at..at + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    };
+    let truncated = || "archive is truncated".to_string();
+
+    // The end-of-central-directory record, followed by at most a 64 KiB comment.
+    let eocd = (0..archive.len().saturating_sub(21))
+        .rev()
+        .take(65_536 + 22)
+        .find(|&at| archive[at..].starts_with(&[0x50, 0x4b, 0x05, 0x06]))
+        .ok_or("not a zip archive")?;
+    let entries = u16_at(eocd + 10).ok_or_else(truncated)?;
+    let mut at = u32_at(eocd + 16).ok_or_else(truncated)?;
+
+    for _ in 0..entries {
+        if !archive.get(at..).is_some_and(|rest| rest.starts_with(&[0x50, 0x4b, 0x01, 0x02])) {
+            return Err("corrupt central directory".to_string());
+        }
+        let method = u16_at(at + 10).ok_or_else(truncated)?;
+        let compressed_len = u32_at(at + 20).ok_or_else(truncated)?;
+        let len = u32_at(at + 24).ok_or_else(truncated)?;
+        let name_len = u16_at(at + 28).ok_or_else(truncated)?;
+        let extra_len = u16_at(at + 30).ok_or_else(truncated)?;
+        let comment_len = u16_at(at + 32).ok_or_else(truncated)?;
+        let local = u32_at(at + 42).ok_or_else(truncated)?;
+        let entry_name = archive.get(at + 46..at + 46 + name_len).ok_or_else(truncated)?;
+        at += 46 + name_len + extra_len + comment_len;
+
+        if entry_name.rsplit(|&b| b == b'/').next() != Some(name.as_bytes()) {
+            continue;
+        }
+        let data_start = local
+            + 30
+            + u16_at(local + 26).ok_or_else(truncated)?
+            + u16_at(local + 28).ok_or_else(truncated)?;
+        let data = archive
+            .get(data_start..data_start + compressed_len)
+            .ok_or_else(truncated)?;
+        let contents = match method {
+            0 => data.to_vec(),
+            8 => miniz_oxide::inflate::decompress_to_vec(data)
+                .map_err(|e| format!("cannot inflate {name}: {e:?}"))?,
+            other => return Err(format!("{name} uses unsupported compression method {other}")),
+        };
+        if contents.len() != len {
+            return Err(format!("{name} unpacked to {} bytes, expected {len}", contents.len()));
+        }
+        return Ok(contents);
+    }
+    Err(format!("archive has no {name}"))
+}
+
+/// Read the file called `name` (in any directory) out of a gzipped tar
+/// archive.  Plain ustar entries are enough for release archives.
+fn extract_from_tar_gz(archive: &[u8], name: &str) -> Result<Vec<u8>> {
+    // The gzip header: magic, method 8 (deflate), flags, then optional
+    // fields before the deflate stream.  An 8-byte trailer follows it.
+    if archive.len() < 18 || archive[..3] != [0x1f, 0x8b, 8] {
+        return Err("not a gzip archive".to_string());
+    }
+    let flags = archive[3];
+    let mut at = 10;
+    if flags & 0x04 != 0 {
+        let extra_len = u16::from_le_bytes([archive[at], archive[at + 1]]) as usize;
+        at += 2 + extra_len;
+    }
+    for flag in [0x08, 0x10] {
+        // File name and comment, each NUL-terminated.
+        if flags & flag != 0 {
+            let len = archive.get(at..).and_then(|rest| rest.iter().position(|&b| b == 0));
+            at += len.ok_or("archive is truncated")? + 1;
+        }
+    }
+    if flags & 0x02 != 0 {
+        at += 2;
+    }
+    let stream = archive
+        .get(at..archive.len() - 8)
+        .ok_or("archive is truncated")?;
+    let tar = miniz_oxide::inflate::decompress_to_vec(stream).map_err(|e| format!("cannot inflate: {e:?}"))?;
+    let trailer = &archive[archive.len() - 4..];
+    if u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]) != tar.len() as u32 {
+        return Err("archive is truncated".to_string());
+    }
+
+    // 512-byte headers, each followed by its data padded to 512 bytes.
+    let field = |header: &[u8], range: std::ops::Range<usize>| {
+        let bytes = &header[range];
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+    };
+    let mut at = 0;
+    while let Some(header) = tar.get(at..at + 512) {
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let size = usize::from_str_radix(&field(header, 124..136), 8)
+            .map_err(|_| "corrupt tar header".to_string())?;
+        let data_start = at + 512;
+        at = data_start + size.div_ceil(512) * 512;
+
+        let regular_file = matches!(header[156], b'0' | 0);
+        let entry_name = field(header, 0..100);
+        if !regular_file || entry_name.rsplit('/').next() != Some(name) {
+            continue;
+        }
+        return tar
+            .get(data_start..data_start + size)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| "archive is truncated".to_string());
+    }
+    Err(format!("archive has no {name}"))
+}
+
+/// Initialization options from the shorthands in `lsp.velvet.settings`:
+/// `semantic_tokens` (`"full"`, `"syntax"` or `"none"`) and `inlay_hints`,
+/// an object of booleans such as `enable_type_hints`.  Like all
+/// initialization options they take effect when velvet restarts.
+fn settings_init_options(worktree: &zed::Worktree) -> Result<Option<zed::serde_json::Value>> {
+    let Some(settings) = zed::settings::LspSettings::for_worktree("velvet", worktree)
+        .ok()
+        .and_then(|s| s.settings)
+    else {
+        return Ok(None);
+    };
+    let mut options = zed::serde_json::json!({});
+
+    let semantic_tokens = &settings["semantic_tokens"];
+    if !semantic_tokens.is_null() {
+        let mode = semantic_tokens
+            .as_str()
+            .filter(|mode| ["full", "syntax", "none"].contains(mode))
+            .ok_or_else(|| {
+                format!(
+                    "Invalid lsp.velvet.settings.semantic_tokens: {semantic_tokens}. \
+                     Expected \"full\", \"syntax\" or \"none\"."
+                )
+            })?;
+        options["enable_semantic_tokens"] = mode.into();
+    }
+
+    if let Some(hints) = settings["inlay_hints"].as_object() {
+        for (key, value) in hints {
+            let on = value.as_bool().ok_or_else(|| {
+                format!("Invalid lsp.velvet.settings.inlay_hints.{key}: {value}. Expected true or false.")
+            })?;
+            options["inlay_hints"][key] = on.into();
+        }
+    }
+
+    Ok(Some(options))
+}
+
+/// Whether to look for newer velvet releases: on unless
+/// `lsp.velvet.settings.check_updates` is `false`, e.g. on offline machines.
+fn update_checks_enabled(worktree: &zed::Worktree) -> bool {
+    zed::settings::LspSettings::for_worktree("velvet", worktree)
+        .ok()
+        .and_then(|s| s.settings)
+        .and_then(|settings| settings["check_updates"].as_bool())
+        .unwrap_or(true)
+}
+
+/// Whether a release asset without a `.sha256` file may be installed
+/// unverified: off unless `lsp.velvet.settings.allow_unverified_download` is
+/// `true`.
+fn unverified_download_allowed(worktree: &zed::Worktree) -> bool {
+    zed::settings::LspSettings::for_worktree("velvet", worktree)
+        .ok()
+        .and_then(|s| s.settings)
+        .and_then(|settings| settings["allow_unverified_download"].as_bool())
+        .unwrap_or(false)
+}
+
+/// Whether velvet may be built from source when no prebuilt release can be
+/// used: off unless `lsp.velvet.settings.build_from_source` is `true`, as
+/// the build takes a few minutes.
+fn build_from_source_enabled(worktree: &zed::Worktree) -> bool {
+    zed::settings::LspSettings::for_worktree("velvet", worktree)
+        .ok()
+        .and_then(|s| s.settings)
+        .and_then(|settings| settings["build_from_source"].as_bool())
+        .unwrap_or(false)
+}
+
+/// Run `command` with `args`, failing with what it printed unless it exits
+/// with status 0.
+fn run_tool(command: &str, args: &[&str], env: &[(String, String)]) -> Result<()> {
+    let output = zed::process::Command::new(command)
+        .args(args.iter().copied())
+        .envs(env.iter().cloned())
+        .output()
+        .map_err(|e| format!("cannot run {command}: {e}"))?;
+    if output.status == Some(0) {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let message = [stderr.trim(), stdout.trim()].into_iter().find(|m| !m.is_empty()).unwrap_or("no output");
+    Err(format!("{command} {} failed: {message}", args.join(" ")))
+}
+
+/// The locale velvet should report messages in, from
+/// `lsp.velvet.settings.locale`: a locale name such as `de_DE.UTF-8` or
+/// `pt-BR`, or `"system"` for the worktree shell's `LC_ALL`, `LC_MESSAGES` or
+/// `LANG`.  `None`, the default, leaves velvet's language alone.
+fn velvet_locale(worktree: &zed::Worktree) -> Option<String> {
+    let settings = zed::settings::LspSettings::for_worktree("velvet", worktree)
+        .ok()
+        .and_then(|s| s.settings)?;
+    let locale = settings["locale"].as_str()?.trim();
+    if locale != "system" {
+        return Some(locale.to_string()).filter(|l| !l.is_empty());
+    }
+    let shell_env = worktree.shell_env();
+    ["LC_ALL", "LC_MESSAGES", "LANG"].iter().find_map(|name| {
+        shell_env
+            .iter()
+            .find(|(key, value)| key == name && !value.is_empty())
+            .map(|(_, value)| value.clone())
+            // The C locale means "untranslated", which is velvet's default.
+            .filter(|value| value != "C" && value != "POSIX")
+    })
+}
+
+/// `locale` as a BCP 47 tag, the form LSP uses: `de_DE.UTF-8` → `de-DE`.
+fn locale_tag(locale: &str) -> String {
+    locale.split(['.', '@']).next().unwrap_or(locale).replace('_', "-")
+}
+
+/// A velvet downloaded by `download_velvet` in an earlier session, if any.
+fn downloaded_velvet() -> Option<String> {
+    std::fs::read_dir(".").ok()?.flatten().find_map(|entry| {
+        let dir = entry.file_name().to_string_lossy().into_owned();
+        if !dir.starts_with("velvet-") {
+            return None;
+        }
+        ["velvet", "velvet.exe"]
+            .iter()
+            .map(|name| format!("{dir}/{name}"))
+            .find(|path| std::fs::metadata(path).is_ok_and(|m| m.is_file()))
+    })
+}
+
+/// Whether version `local` is older than `remote`, comparing dot-separated
+/// numbers (`0.5.10` is newer than `0.5.9`).  Versions that are not plain
+/// numbers are only compared for equality.
+fn is_older_version(local: &str, remote: &str) -> bool {
+    let parse = |v: &str| -> Option<Vec<u64>> {
+        v.split(['-', '+']).next()?.split('.').map(|part| part.parse().ok()).collect()
+    };
+    match (parse(local), parse(remote)) {
+        (Some(local), Some(remote)) => local < remote,
+        _ => local != remote,
+    }
+}
+
+/// A velvet vendored in the project: in `bin/`, `tools/bin/` or `.bin/` under
+/// the worktree root, checked in that order.
+fn find_velvet_in_project(worktree: &zed::Worktree, binary_name: &str, windows: bool) -> Option<String> {
+    let sep = if windows { '\\' } else { '/' };
+    let root = worktree.root_path();
+    let root = root.trim_end_matches(['/', '\\']);
+    ["bin", "tools/bin", ".bin"]
+        .iter()
+        .map(|dir| format!("{root}{sep}{}{sep}{binary_name}", dir.replace('/', &sep.to_string())))
+        .find(|candidate| host_file_exists(candidate, windows))
+        .inspect(|path| eprintln!("[v-enhanced] using the project's velvet at {path}"))
+}
+
+/// `path` from a setting, with a relative path taken from the worktree root.
+fn worktree_path(worktree: &zed::Worktree, path: &str) -> String {
+    if is_absolute(path) {
+        return path.to_string();
+    }
+    let sep = if zed::current_platform().0 == zed::Os::Windows { '\\' } else { '/' };
+    let root = worktree.root_path();
+    format!("{}{sep}{path}", root.trim_end_matches(['/', '\\']))
+}
+
+/// Whether `path` is absolute (or `~`-relative) on any platform.
+fn is_absolute(path: &str) -> bool {
+    path.starts_with(['/', '\\', '~']) || path.get(1..3).is_some_and(|s| s == ":\\" || s == ":/")
+}
+
+/// Whether the velvet at `path` is still there: a relative path is in the
+/// extension's work directory, which it can read itself; anything else has
+/// to be asked of the shell.
+fn velvet_exists(path: &str, windows: bool) -> bool {
+    if is_absolute(path) {
+        host_file_exists(path, windows)
+    } else {
+        std::fs::metadata(path).is_ok_and(|m| m.is_file())
+    }
+}
+
+/// Whether `path` is a file, asked of the shell: the extension cannot read
+/// outside its own directory, so `std::fs` would say no for every path in the
+/// project or the home directory.
+fn host_file_exists(path: &str, windows: bool) -> bool {
+    let mut command = if windows {
+        zed::process::Command::new("cmd").args(["/C", "if", "exist", path, "echo", "found"])
+    } else {
+        zed::process::Command::new("test").args(["-f", path])
+    };
+    command.output().is_ok_and(|output| {
+        if windows {
+            String::from_utf8_lossy(&output.stdout).trim() == "found"
+        } else {
+            output.status == Some(0)
+        }
+    })
+}
+
+/// Look for velvet where installers put it off PATH: velvet's own install
+/// directory (`~/.config/velvet/bin`), then `$VMODULES/bin` and
+/// `~/.vmodules/bin`.  Windows uses the same layout under `%USERPROFILE%`.
+fn find_velvet_in_install_dirs(worktree: &zed::Worktree, binary_name: &str, windows: bool) -> Option<String> {
+    let shell_env = worktree.shell_env();
+    let var = |name: &str| {
+        shell_env
+            .iter()
+            .find(|(key, value)| key == name && !value.is_empty())
+            .map(|(_, value)| value.trim_end_matches(['/', '\\']).to_string())
+    };
+    let sep = if windows { '\\' } else { '/' };
+    let home = var(if windows { "USERPROFILE" } else { "HOME" });
+
+    let mut dirs = Vec::new();
+    if let Some(home) = &home {
+        dirs.push(format!("{home}{sep}.config{sep}velvet{sep}bin"));
+    }
+    if let Some(vmodules) = var("VMODULES") {
+        dirs.push(format!("{vmodules}{sep}bin"));
+    }
+    if let Some(home) = &home {
+        dirs.push(format!("{home}{sep}.vmodules{sep}bin"));
+    }
+
+    dirs.into_iter()
+        .map(|dir| format!("{dir}{sep}{binary_name}"))
+        .find(|candidate| host_file_exists(candidate, windows))
+        .inspect(|path| eprintln!("[v-enhanced] velvet is not on PATH; using {path}"))
+}
+
+/// `VEXE`, `VROOT` and `VMODULES` for velvet: from the worktree's shell
+/// environment where set, else `VEXE` and `VROOT` from the `v` on PATH.
+fn toolchain_env(worktree: &zed::Worktree) -> Vec<(String, String)> {
+    let mut env: Vec<(String, String)> = worktree
+        .shell_env()
+        .into_iter()
+        .filter(|(key, _)| matches!(key.as_str(), "VEXE" | "VROOT" | "VMODULES"))
+        .collect();
+    let has = |env: &[(String, String)], key: &str| env.iter().any(|(k, _)| k == key);
+
+    if let Some(vexe) = worktree.which("v") {
+        if !has(&env, "VEXE") {
+            env.push(("VEXE".to_string(), vexe.clone()));
+        }
+        // A V checkout keeps `v` at its root.  A `v` in a `bin` directory is
+        // usually a `v symlink` link, whose parent is not VROOT; the symlink
+        // cannot be followed from the extension sandbox, so leave it to velvet.
+        let dir = vexe.rsplit_once(['/', '\\']).map(|(dir, _)| dir);
+        if let Some(dir) = dir.filter(|dir| !dir.ends_with("bin")) {
+            if !has(&env, "VROOT") {
+                env.push(("VROOT".to_string(), dir.to_string()));
+            }
+        }
+    }
+    env
+}
+
+/// Set `key` in `env`, replacing an existing value.
+fn set_env(env: &mut Vec<(String, String)>, key: String, value: String) {
+    match env.iter_mut().find(|(k, _)| *k == key) {
+        Some(entry) => entry.1 = value,
+        None => env.push((key, value)),
+    }
+}
+
 /// Recursively merge `src` into `dst`.  Object keys in `src` overwrite keys in
 /// `dst`; for nested objects the merge is recursive so individual sub-keys can
-/// be overridden without replacing the whole object.
+/// be overridden without replacing the whole object.  Arrays and scalars
+/// replace what was there, and keys `dst` lacks are added.  A `src` that is
+/// not an object (`initialization_options: true`) is ignored.
 fn merge_json(dst: &mut zed::serde_json::Value, src: zed::serde_json::Value) {
-    if let (Some(dst_obj), Some(src_obj)) = (dst.as_object_mut(), src.as_object()) {
-        for (key, src_val) in src_obj {
-            let dst_entry = dst_obj.entry(key.clone()).or_insert(zed::serde_json::Value::Null);
-            if dst_entry.is_object() && src_val.is_object() {
-                merge_json(dst_entry, src_val.clone());
-            } else {
-                *dst_entry = src_val.clone();
+    let (Some(dst_obj), zed::serde_json::Value::Object(src_obj)) = (dst.as_object_mut(), &src) else {
+        eprintln!("[v-enhanced] ignoring options that are not an object: {src}");
+        return;
+    };
+    for (key, src_val) in src_obj {
+        match dst_obj.get_mut(key) {
+            Some(dst_val) if dst_val.is_object() && src_val.is_object() => merge_json(dst_val, src_val.clone()),
+            Some(dst_val) => *dst_val = src_val.clone(),
+            None => {
+                dst_obj.insert(key.clone(), src_val.clone());
             }
         }
     }
@@ -288,6 +1574,285 @@ fn extract_version(line: &str) -> Option<String> {
     None
 }
 
+// --- Completion labels -------------------------------------------------------
+
+/// A label for a velvet completion, highlighted as V code: functions and
+/// methods with their signature, fields with their type and enum values
+/// with their enum.  Other kinds keep Zed's default label.
+fn completion_label(completion: &zed::lsp::Completion) -> Option<zed::CodeLabel> {
+    use zed::lsp::CompletionKind;
+
+    let name = completion.label.as_str();
+    let details = completion.label_details.as_ref();
+    let detail = completion
+        .detail
+        .as_deref()
+        .or_else(|| details.and_then(|d| d.detail.as_deref()))
+        .map(str::trim)
+        .filter(|d| !d.is_empty());
+
+    match completion.kind? {
+        CompletionKind::Function | CompletionKind::Method => {
+            // velvet sends either the whole declaration (`fn name(a int) T`)
+            // or just what follows the name.
+            let signature = [detail, details.and_then(|d| d.detail.as_deref())]
+                .into_iter()
+                .flatten()
+                .find_map(|d| signature_after_name(d.trim(), name))?;
+            let code = format!("fn {name}{signature}");
+            Some(zed::CodeLabel {
+                spans: vec![zed::CodeLabelSpan::code_range(3..code.len())],
+                filter_range: (0..name.len()).into(),
+                code,
+            })
+        }
+        CompletionKind::Field | CompletionKind::Property => {
+            let ty = detail?;
+            let prefix = "struct S {\n\t";
+            let code = format!("{prefix}{name} {ty}\n}}");
+            let start = prefix.len();
+            Some(zed::CodeLabel {
+                spans: vec![zed::CodeLabelSpan::code_range(start..start + name.len() + 1 + ty.len())],
+                filter_range: (0..name.len()).into(),
+                code,
+            })
+        }
+        CompletionKind::EnumMember => {
+            let parent = details
+                .and_then(|d| d.description.as_deref())
+                .or(detail)
+                // `Color`, `Color.red` or `main.Color`: keep just `Color`.
+                .map(|p| {
+                    let p = p.strip_suffix(name).and_then(|p| p.strip_suffix('.')).unwrap_or(p);
+                    p.rsplit('.').next().unwrap_or(p).trim()
+                })
+                .filter(|p| !p.is_empty() && *p != name)?;
+            let code = format!("{parent}.{name}");
+            Some(zed::CodeLabel {
+                spans: vec![zed::CodeLabelSpan::code_range(0..code.len())],
+                filter_range: (parent.len() + 1..code.len()).into(),
+                code,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The parameters and return type in `detail`: what follows `name` (or its
+/// generic parameters) in a full signature, or `detail` itself if it starts
+/// with the parameter list.
+fn signature_after_name<'a>(detail: &'a str, name: &str) -> Option<&'a str> {
+    if detail.starts_with(['(', '[']) {
+        return Some(detail);
+    }
+    detail
+        .match_indices(name)
+        .map(|(at, _)| &detail[at + name.len()..])
+        .find(|rest| rest.starts_with(['(', '[']))
+}
+
+/// A label for a workspace or document symbol, highlighted as V code:
+/// `struct Name`, `enum Name`, `const name`, `fn name` and methods as
+/// `(Receiver) name`.  velvet sends only the name, which for methods may be
+/// qualified (`Stack.push`) and may carry generic parameters (`max[T]`);
+/// they are kept as they are.
+fn symbol_label(symbol: &zed::lsp::Symbol) -> Option<zed::CodeLabel> {
+    use zed::lsp::SymbolKind;
+
+    let name = symbol.name.as_str();
+    let keyword = match symbol.kind {
+        SymbolKind::Struct | SymbolKind::Class => "struct",
+        SymbolKind::Enum => "enum",
+        SymbolKind::Interface => "interface",
+        SymbolKind::Constant => "const",
+        SymbolKind::Function | SymbolKind::Constructor => "fn",
+        SymbolKind::Method => {
+            // `Stack.push` → `(Stack) push`; `Stack[T].push` keeps its `[T]`.
+            let Some(dot) = receiver_dot(name) else {
+                return keyword_label("fn", name);
+            };
+            let (receiver, method) = (&name[..dot], &name[dot + 1..]);
+            let code = format!("fn (_ {receiver}) {method}");
+            let receiver_start = "fn (_ ".len();
+            let method_start = receiver_start + receiver.len() + 2;
+            return Some(zed::CodeLabel {
+                spans: vec![
+                    zed::CodeLabelSpan::literal("(", None),
+                    zed::CodeLabelSpan::code_range(receiver_start..receiver_start + receiver.len()),
+                    zed::CodeLabelSpan::literal(") ", None),
+                    zed::CodeLabelSpan::code_range(method_start..code.len()),
+                ],
+                filter_range: (receiver.len() + 3..receiver.len() + 3 + method.len()).into(),
+                code,
+            });
+        }
+        _ => return None,
+    };
+    keyword_label(keyword, name)
+}
+
+/// The `.` separating a method's receiver from its name: the last one
+/// outside brackets and before any parameter list.
+fn receiver_dot(name: &str) -> Option<usize> {
+    let mut depth = 0i32;
+    let mut dot = None;
+    for (i, c) in name.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            '(' if depth == 0 => break,
+            '.' if depth == 0 => dot = Some(i),
+            _ => {}
+        }
+    }
+    dot
+}
+
+/// `<keyword> <name>` as V code, filtered on the name.
+fn keyword_label(keyword: &str, name: &str) -> Option<zed::CodeLabel> {
+    let code = format!("{keyword} {name}");
+    Some(zed::CodeLabel {
+        spans: vec![zed::CodeLabelSpan::code_range(0..code.len())],
+        filter_range: (keyword.len() + 1..code.len()).into(),
+        code,
+    })
+}
+
+// --- /v-doc ----------------------------------------------------------------
+
+/// Run `v doc <args>`, returning its output or, if it fails, its error.
+fn v_doc(v: &str, env: &[(String, String)], args: &[&str]) -> Result<String> {
+    let output = zed::process::Command::new(v)
+        .arg("doc")
+        .args(args.iter().copied())
+        .envs(env.iter().cloned())
+        .output()
+        .map_err(|e| format!("cannot run {v} doc: {e}"))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if output.status == Some(0) && !stdout.trim().is_empty() {
+        return Ok(stdout.into_owned());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let message = [stderr.trim(), stdout.trim()].into_iter().find(|m| !m.is_empty()).unwrap_or("no output");
+    Err(format!("v doc {} failed: {message}", args.join(" ")))
+}
+
+/// `docs` cut to at most `max` bytes at a line boundary, with a note saying
+/// how much was left out.
+fn truncate_docs(docs: &str, max: usize) -> String {
+    if docs.len() <= max {
+        return docs.to_string();
+    }
+    let mut end = max;
+    while !docs.is_char_boundary(end) {
+        end -= 1;
+    }
+    let end = docs[..end].rfind('\n').unwrap_or(end);
+    let omitted = docs[end..].lines().filter(|line| !line.is_empty()).count();
+    format!("{}\n\n... truncated, {omitted} more lines", &docs[..end])
+}
+
+/// The modules under `$VROOT/vlib/<parent>` (`parent` dotted, as in `net`),
+/// listed with the shell since the extension cannot read outside its own
+/// directory.  Empty when the listing fails.
+fn vlib_modules(vroot: &str, parent: &str) -> Vec<String> {
+    let windows = zed::current_platform().0 == zed::Os::Windows;
+    let sep = if windows { "\\" } else { "/" };
+    let mut dir = format!("{vroot}{sep}vlib");
+    for part in parent.split('.').filter(|part| !part.is_empty()) {
+        dir = format!("{dir}{sep}{part}");
+    }
+    let mut command = if windows {
+        zed::process::Command::new("cmd").args(["/C", "dir", "/B", "/AD", dir.as_str()])
+    } else {
+        zed::process::Command::new("ls").args(["-1p", dir.as_str()])
+    };
+    let Ok(output) = command.output() else {
+        return Vec::new();
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        // `ls -p` marks directories with a `/`; `dir /AD` lists only those.
+        .filter_map(|line| if windows { Some(line.trim()) } else { line.trim().strip_suffix('/') })
+        .filter(|name| !name.is_empty() && !name.starts_with('.') && *name != "tests")
+        .map(str::to_string)
+        .collect()
+}
+
+// --- Debugging -------------------------------------------------------------
+
+/// Where `v` puts the binary built from `target` when not given `-o`: a
+/// `.v`/`.vsh` file's path without the extension, or `dir/dir` for a module
+/// directory.  A relative `target` is taken from `cwd`.
+fn v_output_path(target: &str, cwd: Option<&str>, windows: bool) -> String {
+    let sep = if windows { '\\' } else { '/' };
+    let target = target.trim_end_matches(['/', '\\']);
+    let relative = !target.starts_with(['/', '\\']) && target.get(1..2) != Some(":");
+    let target = match cwd.map(|cwd| cwd.trim_end_matches(['/', '\\'])) {
+        Some(cwd) if target == "." || target.is_empty() => cwd.to_string(),
+        Some(cwd) if relative => format!("{cwd}{sep}{target}"),
+        _ => target.to_string(),
+    };
+    let program = match target.strip_suffix(".vsh").or_else(|| target.strip_suffix(".v")) {
+        Some(stem) => stem.to_string(),
+        None => {
+            let name = target.rsplit(['/', '\\']).next().unwrap_or(&target);
+            format!("{target}{sep}{name}")
+        }
+    };
+    if windows {
+        format!("{program}.exe")
+    } else {
+        program
+    }
+}
+
+/// `value`, or `default` when it is missing or `null`.
+fn non_null(value: &zed::serde_json::Value, default: zed::serde_json::Value) -> zed::serde_json::Value {
+    if value.is_null() {
+        default
+    } else {
+        value.clone()
+    }
+}
+
 // --- Registration ------------------------------------------------------------
 
 zed::register_extension!(VEnhancedExtension);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zed::serde_json::json;
+
+    #[test]
+    fn merge_json_overrides_nested_keys() {
+        let mut dst = json!({ "inlay_hints": { "enable": true, "enable_type_hints": true } });
+        merge_json(&mut dst, json!({ "inlay_hints": { "enable_type_hints": false } }));
+        assert_eq!(dst, json!({ "inlay_hints": { "enable": true, "enable_type_hints": false } }));
+    }
+
+    #[test]
+    fn merge_json_adds_user_keys() {
+        let mut dst = json!({ "enable_semantic_tokens": "full" });
+        merge_json(&mut dst, json!({ "custom": { "level": 2 } }));
+        assert_eq!(dst, json!({ "enable_semantic_tokens": "full", "custom": { "level": 2 } }));
+    }
+
+    #[test]
+    fn merge_json_ignores_non_object_options() {
+        let defaults = json!({ "enable_semantic_tokens": "full", "inlay_hints": { "enable": true } });
+        for options in [json!(true), json!(null), json!(["a"]), json!("full")] {
+            let mut dst = defaults.clone();
+            merge_json(&mut dst, options);
+            assert_eq!(dst, defaults);
+        }
+    }
+
+    #[test]
+    fn merge_json_replaces_arrays() {
+        let mut dst = json!({ "vmodules": ["a", "b", "c"] });
+        merge_json(&mut dst, json!({ "vmodules": ["d"] }));
+        assert_eq!(dst, json!({ "vmodules": ["d"] }));
+    }
+}
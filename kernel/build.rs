@@ -1,3 +1,44 @@
+use std::{fs, path::PathBuf, process::Command};
+
 fn main() {
-    println!("cargo:rustc-link-lib=dylib=advapi32");
-}
\ No newline at end of file
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("windows") {
+        println!("cargo:rustc-link-lib=dylib=advapi32");
+    }
+
+    // Embed the short commit hash when building from a git checkout, so
+    // kernel_info can say exactly which build is running.
+    let commit = git(&["rev-parse", "--short", "HEAD"]);
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=V_KERNEL_GIT_COMMIT={commit}");
+    }
+
+    // Rebuild when HEAD moves, by checkout or by commit, so the hash is
+    // never stale.  Naming any file stops cargo from watching the package
+    // sources for this script, so the script itself is listed too.
+    println!("cargo:rerun-if-changed=build.rs");
+    if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]).map(PathBuf::from) {
+        let head = git_dir.join("HEAD");
+        println!("cargo:rerun-if-changed={}", head.display());
+        let target = fs::read_to_string(&head).ok();
+        if let Some(reference) = target.as_deref().and_then(|h| h.trim().strip_prefix("ref: ")) {
+            println!("cargo:rerun-if-changed={}", git_dir.join(reference).display());
+            // A ref that `git gc` packed lives here instead.  Cargo reruns
+            // the script every build for a file that does not exist.
+            let packed = git_dir.join("packed-refs");
+            if packed.exists() {
+                println!("cargo:rerun-if-changed={}", packed.display());
+            }
+        }
+    }
+}
+
+/// The trimmed output of `git <args>`, if it succeeds.
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .map(|s| s.trim().to_string())
+}
@@ -0,0 +1,109 @@
+//! What the V toolchain supports.
+//!
+//! Probed once when the kernel starts, and again when `%vversion` switches
+//! compilers, instead of whenever a feature needs to know.  `%capabilities`
+//! shows the result and it is logged after every probe, so the answer to
+//! "why doesn't this work with my V?" is in one place.
+//!
+//! Probes only ask V (`v version`, `v help`) or run it on a one-line file,
+//! never compile a program, so they add little to startup.
+
+use crate::v_version;
+use std::{fs, path::Path, process::Command};
+
+/// Subcommands worth knowing about, in the order they are listed.  Not every
+/// V build has all of them: `ls` and `watch` are recent, and packaged V
+/// sometimes leaves tools out.
+const COMMANDS: &[&str] = &["repl", "fmt", "vet", "doc", "test", "watch", "ls"];
+
+#[derive(Debug)]
+pub struct VCapabilities {
+    /// `v version`, or why it could not be run.
+    pub version: Result<String, String>,
+    /// The entries of `COMMANDS` that `v help` lists.
+    pub commands: Vec<&'static str>,
+    /// `v -check-syntax` parses a file without compiling it.
+    pub check_syntax: bool,
+}
+
+impl VCapabilities {
+    /// Ask the `v` at `v` what it supports.  `scratch` is a directory the
+    /// syntax probe can write a file to.
+    pub fn probe(v: &Path, scratch: &Path) -> Self {
+        let version = v_version(v);
+        if version.is_err() {
+            // Nothing else can work either; don't wait on more failures.
+            return VCapabilities {
+                version,
+                commands: Vec::new(),
+                check_syntax: false,
+            };
+        }
+        VCapabilities {
+            version,
+            commands: probe_commands(v),
+            check_syntax: probe_check_syntax(v, scratch),
+        }
+    }
+
+    /// The `%capabilities` listing, one line per capability.
+    pub fn format(&self) -> String {
+        let yes_no = |on: bool| if on { "yes" } else { "no" };
+        let version = match &self.version {
+            Ok(version) => version.clone(),
+            Err(e) => format!("unavailable ({e})"),
+        };
+        let missing: Vec<&str> = COMMANDS
+            .iter()
+            .copied()
+            .filter(|c| !self.commands.contains(c))
+            .collect();
+        let mut out = format!("[v-kernel] V: {version}\n");
+        out.push_str(&format!("[v-kernel] Commands: {}\n", list(&self.commands)));
+        if !missing.is_empty() {
+            out.push_str(&format!("[v-kernel] Not available: {}\n", list(&missing)));
+        }
+        out.push_str(&format!("[v-kernel] -check-syntax: {}\n", yes_no(self.check_syntax)));
+        out
+    }
+}
+
+fn list(items: &[&str]) -> String {
+    if items.is_empty() {
+        "none".to_string()
+    } else {
+        items.join(", ")
+    }
+}
+
+/// The `COMMANDS` that `v help` lists: lines of its output whose first word
+/// is the command name.
+fn probe_commands(v: &Path) -> Vec<&'static str> {
+    let Ok(output) = Command::new(v).arg("help").output() else {
+        return Vec::new();
+    };
+    // Older V prints help to stderr.
+    let mut help = String::from_utf8_lossy(&output.stdout).into_owned();
+    help.push_str(&String::from_utf8_lossy(&output.stderr));
+    COMMANDS
+        .iter()
+        .copied()
+        .filter(|c| help.lines().any(|line| line.split_whitespace().next() == Some(c)))
+        .collect()
+}
+
+/// Whether `v -check-syntax` accepts a trivial file.  A V without the flag
+/// rejects it as an unknown argument.
+fn probe_check_syntax(v: &Path, scratch: &Path) -> bool {
+    let file = scratch.join("probe_check_syntax.v");
+    if fs::write(&file, "fn main() {}\n").is_err() {
+        return false;
+    }
+    let ok = Command::new(v)
+        .arg("-check-syntax")
+        .arg(&file)
+        .output()
+        .is_ok_and(|o| o.status.success());
+    fs::remove_file(&file).ok();
+    ok
+}
@@ -0,0 +1,351 @@
+//! Kernel configuration.
+//!
+//! Settings are read once at startup from `V_KERNEL_*` environment variables.
+//! Settings default to the kernel's historical behaviour unless noted, so a
+//! kernel launched without any of these variables behaves as before.
+
+use crate::log::log;
+use std::{env, path::PathBuf, str::FromStr, time::Duration};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Shut the kernel down after this long without a shell or control
+    /// message.  `None` (the default) disables the idle watchdog.
+    ///
+    /// Env: `V_KERNEL_IDLE_TIMEOUT_MINS` (whole minutes, `0` = disabled).
+    pub idle_timeout: Option<Duration>,
+
+    /// Bind the (unused by V) stdin socket.  Embedded launchers that never
+    /// connect to it can turn this off.
+    ///
+    /// Env: `V_KERNEL_BIND_STDIN` (default on).
+    pub bind_stdin: bool,
+
+    /// MIME types allowed in `display_data` bundles.  `text/plain` is always
+    /// kept as the fallback, so `["text/plain"]` drops HTML and images
+    /// entirely for plain-text front-ends.  `None` (the default) keeps all.
+    ///
+    /// Env: `V_KERNEL_MIME_TYPES` (comma-separated, e.g. `text/plain`).
+    pub mime_types: Option<Vec<String>>,
+
+    /// Append kernel logs to this file instead of writing them to stderr.
+    ///
+    /// Env: `V_KERNEL_LOG_FILE`.  The `--log-file <path>` flag overrides it.
+    pub log_file: Option<PathBuf>,
+
+    /// When a cell ends with an assignment (`x := f()`), show the assigned
+    /// value(s) as the cell's result.  Off by default, matching Jupyter.
+    ///
+    /// Env: `V_KERNEL_ECHO_ASSIGNMENTS`.
+    pub echo_assignments: bool,
+
+    /// `username` sent in the header of every message the kernel sends,
+    /// for setups that track message provenance by user.
+    ///
+    /// Env: `V_KERNEL_USERNAME` (default `v-kernel`).
+    pub username: String,
+
+    /// The `v` executable cells are compiled with: a path, or a name looked
+    /// up on `PATH`.  `%vversion` can switch it for the current session.
+    ///
+    /// Env: `V_KERNEL_V_BINARY` (default `v`).
+    pub v_binary: PathBuf,
+
+    /// If set, only these magics (written as used: `%reset`, `%%vsh`) may
+    /// run.  `None` (the default) allows every magic.
+    ///
+    /// Env: `V_KERNEL_ENABLED_MAGICS` (comma-separated).
+    pub enabled_magics: Option<Vec<String>>,
+
+    /// Magics that may never run, e.g. shell escapes on a shared server.
+    /// Applied after `enabled_magics`.
+    ///
+    /// Env: `V_KERNEL_DISABLED_MAGICS` (comma-separated).
+    pub disabled_magics: Vec<String>,
+
+    /// Largest incoming message, all frames together, that the kernel will
+    /// accept.  Bigger messages are dropped and logged.
+    ///
+    /// Env: `V_KERNEL_MAX_MESSAGE_MB` (default 256).
+    pub max_message_bytes: usize,
+
+    /// Most frames an incoming message may have (identities, the five
+    /// protocol parts and buffers together).
+    ///
+    /// Env: `V_KERNEL_MAX_MESSAGE_FRAMES` (default 1024).
+    pub max_message_frames: usize,
+
+    /// Most child processes (`v` builds, cell programs) allowed to run at
+    /// once.  Cells run one at a time, so 1 is enough; further spawns are
+    /// refused with an error instead of queued.
+    ///
+    /// Env: `V_KERNEL_MAX_PROCESSES` (default 1).
+    pub max_processes: usize,
+
+    /// Encoding of the output of `v` and cell programs.  `None` (the
+    /// default) means UTF-8, falling back to the system code page on Windows
+    /// for output that is not valid UTF-8.
+    ///
+    /// Env: `V_KERNEL_OUTPUT_ENCODING` (a WHATWG label, e.g. `windows-1251`).
+    pub output_encoding: Option<&'static encoding_rs::Encoding>,
+
+    /// Name of a shared session to join.  Kernels with the same name share
+    /// their accumulated declarations; `None` (the default) keeps the
+    /// kernel fully isolated.
+    ///
+    /// Env: `V_KERNEL_SESSION` (letters, digits, `-` and `_`).
+    pub session: Option<String>,
+
+    /// Execution count the session starts from, so a resumed notebook can
+    /// continue its numbering: the first cell becomes `[start_count + 1]`.
+    /// `%reset` and restarts go back to it as well.
+    ///
+    /// Env: `V_KERNEL_START_COUNT` (default 0).
+    pub start_count: u32,
+
+    /// Longest each `v` build or cell program may run before it is stopped.
+    /// `None` (the default) means no limit.  `%%timeout` overrides it for
+    /// a single cell.
+    ///
+    /// Env: `V_KERNEL_EXEC_TIMEOUT_SECS` (`0` = no limit).
+    pub exec_timeout: Option<Duration>,
+
+    /// Stream a cell program's stdout while it runs, sending what arrived
+    /// within this window as one message.  `None` (the default) sends all
+    /// output when the cell finishes.
+    ///
+    /// Env: `V_KERNEL_STREAM_WINDOW_MS` (`0` = off).
+    pub stream_window: Option<Duration>,
+
+    /// With streaming on, send early once this many bytes are waiting.
+    ///
+    /// Env: `V_KERNEL_STREAM_CHUNK_BYTES` (default 4096).
+    pub stream_chunk_bytes: usize,
+
+    /// When the build fails only because an accumulated import is unused,
+    /// drop that import from the session and build the cell again, once.
+    /// On by default: otherwise one stray import breaks every later cell.
+    ///
+    /// Env: `V_KERNEL_DROP_UNUSED_IMPORTS` (default on).
+    pub drop_unused_imports: bool,
+
+    /// Memory limit for cell programs (not the `v` build), in megabytes.
+    /// `None` (the default) means no limit.  `%memlimit` changes it for the
+    /// session.
+    ///
+    /// Env: `V_KERNEL_MEM_LIMIT_MB` (`0` = no limit).
+    pub mem_limit_mb: Option<u64>,
+
+    /// C compiler `v` builds cells with (passed as `-cc`), as a full path.
+    /// `None` (the default) leaves the choice to `v`.  `%cc` changes it for
+    /// the session.
+    ///
+    /// Env: `V_KERNEL_CC`.
+    pub c_compiler: Option<PathBuf>,
+
+    /// Name of the temporary source file each cell is compiled from, without
+    /// the extension.  `{n}` is replaced with the execution count and
+    /// `{hash}` with a hash of the generated source; a name with neither is
+    /// overwritten by every cell, so tools can watch one file.
+    ///
+    /// Env: `V_KERNEL_SOURCE_NAME` (default `cell_{n}`).
+    pub source_name: String,
+
+    /// Show compiler warnings in cell output.  When off, cells are built
+    /// with `v -w`.  `%warnings` overrides it for the session.
+    ///
+    /// Env: `V_KERNEL_SHOW_WARNINGS` (default on).
+    pub show_warnings: bool,
+
+    /// Leave the session's temporary directory (generated sources and
+    /// binaries) behind when the kernel exits, for debugging.  Off by
+    /// default.
+    ///
+    /// Env: `V_KERNEL_KEEP_TMP`.
+    pub keep_tmp: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            idle_timeout: None,
+            bind_stdin: true,
+            mime_types: None,
+            log_file: None,
+            echo_assignments: false,
+            username: "v-kernel".to_string(),
+            v_binary: PathBuf::from("v"),
+            enabled_magics: None,
+            disabled_magics: Vec::new(),
+            max_message_bytes: 256 * 1024 * 1024,
+            max_message_frames: 1024,
+            max_processes: 1,
+            output_encoding: None,
+            session: None,
+            start_count: 0,
+            exec_timeout: None,
+            stream_window: None,
+            stream_chunk_bytes: 4096,
+            drop_unused_imports: true,
+            mem_limit_mb: None,
+            c_compiler: None,
+            source_name: "cell_{n}".to_string(),
+            show_warnings: true,
+            keep_tmp: false,
+        }
+    }
+}
+
+impl Config {
+    /// Build the configuration from the process environment.
+    pub fn from_env() -> Self {
+        let mut config = Config::default();
+
+        if let Some(mins) = env_parse::<u64>("V_KERNEL_IDLE_TIMEOUT_MINS") {
+            config.idle_timeout = (mins > 0).then(|| Duration::from_secs(mins * 60));
+        }
+        if let Some(on) = env_flag("V_KERNEL_BIND_STDIN") {
+            config.bind_stdin = on;
+        }
+        if let Some(list) = env_var("V_KERNEL_MIME_TYPES") {
+            config.mime_types = Some(env_list(&list));
+        }
+        config.log_file = env_var("V_KERNEL_LOG_FILE").map(PathBuf::from);
+        if let Some(on) = env_flag("V_KERNEL_ECHO_ASSIGNMENTS") {
+            config.echo_assignments = on;
+        }
+        if let Some(username) = env_var("V_KERNEL_USERNAME") {
+            config.username = username;
+        }
+        if let Some(v) = env_var("V_KERNEL_V_BINARY") {
+            config.v_binary = PathBuf::from(v);
+        }
+        if let Some(list) = env_var("V_KERNEL_ENABLED_MAGICS") {
+            config.enabled_magics = Some(env_list(&list));
+        }
+        if let Some(list) = env_var("V_KERNEL_DISABLED_MAGICS") {
+            config.disabled_magics = env_list(&list);
+        }
+        if let Some(mb) = env_parse::<usize>("V_KERNEL_MAX_MESSAGE_MB") {
+            config.max_message_bytes = mb.saturating_mul(1024 * 1024);
+        }
+        if let Some(frames) = env_parse("V_KERNEL_MAX_MESSAGE_FRAMES") {
+            config.max_message_frames = frames;
+        }
+        if let Some(n) = env_parse::<usize>("V_KERNEL_MAX_PROCESSES") {
+            config.max_processes = n.max(1);
+        }
+        if let Some(label) = env_var("V_KERNEL_OUTPUT_ENCODING") {
+            config.output_encoding = encoding_rs::Encoding::for_label(label.as_bytes());
+            if config.output_encoding.is_none() {
+                log!("Ignoring unknown V_KERNEL_OUTPUT_ENCODING: {label:?}");
+            }
+        }
+        if let Some(name) = env_var("V_KERNEL_SESSION") {
+            if crate::session::valid_name(&name) {
+                config.session = Some(name);
+            } else {
+                log!("Ignoring invalid V_KERNEL_SESSION: {name:?}");
+            }
+        }
+        if let Some(n) = env_parse("V_KERNEL_START_COUNT") {
+            config.start_count = n;
+        }
+        if let Some(secs) = env_parse::<u64>("V_KERNEL_EXEC_TIMEOUT_SECS") {
+            config.exec_timeout = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        if let Some(ms) = env_parse::<u64>("V_KERNEL_STREAM_WINDOW_MS") {
+            config.stream_window = (ms > 0).then(|| Duration::from_millis(ms));
+        }
+        if let Some(n) = env_parse::<usize>("V_KERNEL_STREAM_CHUNK_BYTES") {
+            config.stream_chunk_bytes = n.max(1);
+        }
+        if let Some(on) = env_flag("V_KERNEL_DROP_UNUSED_IMPORTS") {
+            config.drop_unused_imports = on;
+        }
+        if let Some(mb) = env_parse::<u64>("V_KERNEL_MEM_LIMIT_MB") {
+            config.mem_limit_mb = (mb > 0).then_some(mb);
+        }
+        if let Some(cc) = env_var("V_KERNEL_CC") {
+            let cc = PathBuf::from(cc);
+            if cc.is_file() {
+                config.c_compiler = Some(cc);
+            } else {
+                log!("Ignoring V_KERNEL_CC, no such file: {}", cc.display());
+            }
+        }
+        if let Some(on) = env_flag("V_KERNEL_SHOW_WARNINGS") {
+            config.show_warnings = on;
+        }
+        if let Some(on) = env_flag("V_KERNEL_KEEP_TMP") {
+            config.keep_tmp = on;
+        }
+        if let Some(name) = env_var("V_KERNEL_SOURCE_NAME") {
+            if name.contains(['/', '\\']) || name.starts_with('.') {
+                log!("Ignoring V_KERNEL_SOURCE_NAME, not a plain file name: {name:?}");
+            } else {
+                config.source_name = name;
+            }
+        }
+
+        config
+    }
+
+    /// Whether the magic `name` (e.g. `%edit`, `%%vsh`) may run.  Without
+    /// `enabled_magics`, that is `by_default`.
+    pub fn magic_enabled(&self, name: &str, by_default: bool) -> bool {
+        let listed = |list: &[String]| list.iter().any(|m| m == name);
+        let enabled = match &self.enabled_magics {
+            Some(list) => listed(list),
+            None => by_default,
+        };
+        enabled && !listed(&self.disabled_magics)
+    }
+}
+
+/// Read an environment variable, treating empty / whitespace-only values as unset.
+fn env_var(name: &str) -> Option<String> {
+    let value = env::var(name).ok()?;
+    let value = value.trim();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+/// Split a comma-separated value into trimmed, non-empty items.
+fn env_list(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Read and parse an environment variable.  Unparseable values are logged and
+/// ignored so a typo never prevents the kernel from starting.
+fn env_parse<T: FromStr>(name: &str) -> Option<T> {
+    let raw = env_var(name)?;
+    match raw.parse() {
+        Ok(v) => Some(v),
+        Err(_) => {
+            log!("Ignoring invalid value for {name}: {raw:?}");
+            None
+        }
+    }
+}
+
+/// Read a boolean environment variable (`1`/`0`, `true`/`false`, `yes`/`no`,
+/// `on`/`off`).
+fn env_flag(name: &str) -> Option<bool> {
+    let raw = env_var(name)?;
+    match raw.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => {
+            log!("Ignoring invalid value for {name}: {raw:?}");
+            None
+        }
+    }
+}
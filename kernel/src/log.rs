@@ -0,0 +1,75 @@
+//! Kernel log output.
+//!
+//! Log lines go to stderr by default.  With `--log-file <path>` (or
+//! `V_KERNEL_LOG_FILE`) they are appended to that file instead, with a
+//! timestamp, so users can attach them to bug reports without hunting for
+//! wherever Zed put the kernel's stderr.
+//!
+//! The connection key is redacted from every line regardless of destination.
+
+use chrono::Utc;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+struct Logger {
+    file: Option<File>,
+    /// HMAC key to scrub from log lines (empty = nothing to redact).
+    secret: String,
+}
+
+static LOGGER: OnceLock<Mutex<Logger>> = OnceLock::new();
+
+/// Log a formatted message, prefixed with `[v-kernel]`.
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::log::write(&format!($($arg)*))
+    };
+}
+pub(crate) use log;
+
+/// Route log output to `file` (or keep stderr when `None`) and register the
+/// connection key for redaction.  Until this is called, lines go to stderr.
+pub fn init(file: Option<&Path>, secret: &str) -> io::Result<()> {
+    let file = match file {
+        Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+        None => None,
+    };
+    let logger = Logger {
+        file,
+        secret: secret.to_string(),
+    };
+    LOGGER
+        .set(Mutex::new(logger))
+        .map_err(|_| io::Error::other("logger already initialised"))
+}
+
+pub fn write(message: &str) {
+    let Some(logger) = LOGGER.get() else {
+        write_stderr(message);
+        return;
+    };
+    let mut logger = logger.lock().unwrap_or_else(|e| e.into_inner());
+
+    let message = if logger.secret.is_empty() {
+        message.to_string()
+    } else {
+        message.replace(&logger.secret, "<redacted>")
+    };
+
+    match &mut logger.file {
+        Some(file) => {
+            writeln!(file, "{} [v-kernel] {message}", Utc::now().to_rfc3339()).ok();
+        }
+        None => write_stderr(&message),
+    }
+}
+
+/// Unlike `eprintln!`, which panics, a closed stderr (the front-end went
+/// away) just loses the line.
+fn write_stderr(message: &str) {
+    writeln!(io::stderr(), "[v-kernel] {message}").ok();
+}
@@ -0,0 +1,358 @@
+//! Magic commands.
+//!
+//! Every magic is one entry in `MAGICS`: its name, arguments, a one-line
+//! description and the handler that runs it.  `execute_cell` hands any cell
+//! that starts with `%` to `dispatch` before classifying it, and `%lsmagic`
+//! lists the same table, so adding a magic means adding one entry here.
+//!
+//! Two shapes of magic exist.  Most line magics (`%reset`, `%p <expr>`) are
+//! the whole cell: everything after the name is the argument.  Cell magics
+//! (`%%vsh`) and a few line magics (`%clear_output`) sit on the first line
+//! and apply to the rest of the cell, the body.
+
+use crate::{
+    config::Config, format_classification, kernel_version, v_version, CellMode, ExecOutput,
+    KernelState,
+};
+use serde_json::json;
+use std::time::Duration;
+
+/// A magic the kernel understands.
+pub struct Magic {
+    /// As written: `%reset`, `%%vsh`.
+    pub name: &'static str,
+    /// Arguments, for the listing (`<name>`, `[-n N] [-r R]`).  Empty for a
+    /// magic that takes none; giving it some is then an error.
+    pub args: &'static str,
+    /// One line saying what it does, for `%lsmagic`.
+    pub description: &'static str,
+    /// The magic is the first line and applies to the rest of the cell.
+    /// Otherwise it is the whole cell.
+    pub takes_body: bool,
+    /// Allowed unless `V_KERNEL_DISABLED_MAGICS` names it.  A magic off by
+    /// default runs only when `V_KERNEL_ENABLED_MAGICS` names it.
+    pub enabled_by_default: bool,
+    /// Runs the magic.
+    pub run: fn(&mut KernelState, Invocation) -> ExecOutput,
+}
+
+/// What a magic was given.
+pub struct Invocation<'a> {
+    /// Everything after the name: on the first line for a magic that takes
+    /// a body, in the whole cell otherwise.  Trimmed.
+    pub args: &'a str,
+    /// The rest of the cell after the first line; empty for a magic that
+    /// does not take a body.
+    pub body: &'a str,
+}
+
+impl Magic {
+    /// A magic that is the whole cell.
+    const fn line(
+        name: &'static str,
+        args: &'static str,
+        description: &'static str,
+        run: fn(&mut KernelState, Invocation) -> ExecOutput,
+    ) -> Self {
+        Magic {
+            name,
+            args,
+            description,
+            takes_body: false,
+            enabled_by_default: true,
+            run,
+        }
+    }
+
+    /// A magic on the first line that applies to the rest of the cell.
+    const fn leading(
+        name: &'static str,
+        args: &'static str,
+        description: &'static str,
+        run: fn(&mut KernelState, Invocation) -> ExecOutput,
+    ) -> Self {
+        Magic {
+            takes_body: true,
+            ..Magic::line(name, args, description, run)
+        }
+    }
+
+    pub fn is_cell_magic(&self) -> bool {
+        self.name.starts_with("%%")
+    }
+
+    /// Whether `config` allows this magic to run.
+    pub fn enabled(&self, config: &Config) -> bool {
+        config.magic_enabled(self.name, self.enabled_by_default)
+    }
+
+    /// `name args`, as shown in usage messages and the listing.
+    fn usage(&self) -> String {
+        if self.args.is_empty() {
+            self.name.to_string()
+        } else {
+            format!("{} {}", self.name, self.args)
+        }
+    }
+}
+
+/// Every magic the kernel supports, in `%lsmagic` order.
+pub const MAGICS: &[Magic] = &[
+    Magic::leading(
+        "%clear_output",
+        "",
+        "Clear the cell's output, then run the rest of the cell",
+        clear_output,
+    ),
+    Magic::line("%reset", "", "Clear accumulated declarations and the execution counter", reset),
+    Magic::leading(
+        "%classify",
+        "",
+        "Show how the rest of the cell splits into declarations and statements",
+        |_, call| ExecOutput::text(format_classification(call.body)),
+    ),
+    Magic::line("%show", "", "Show the accumulated V source", show),
+    Magic::line(
+        "%edit",
+        "<name>",
+        "Open a declaration in a file; saved changes replace it",
+        |state, call| state.open_edit(call.args),
+    ),
+    Magic::line("%p", "<expr>", "Show the value of an expression over the session", |state, call| {
+        if call.args.is_empty() {
+            return ExecOutput::error("Usage: %p <expr>\n");
+        }
+        state.print_value(call.args)
+    }),
+    Magic::line(
+        "%import_session",
+        "<file.v>",
+        "Load a saved V file's declarations and run its statements",
+        |state, call| state.import_session(call.args),
+    ),
+    Magic::line(
+        "%env",
+        "[KEY [VALUE]]",
+        "List, show or set environment variables for cell programs",
+        |state, call| state.env_magic(call.args),
+    ),
+    Magic::line(
+        "%setenv",
+        "KEY=VALUE",
+        "Set an environment variable for cell programs",
+        |state, call| match call.args.split_once('=') {
+            Some((key, value)) => state.set_program_env(key.trim(), value),
+            None => ExecOutput::error("Usage: %setenv KEY=VALUE\n"),
+        },
+    ),
+    Magic::line(
+        "%memlimit",
+        "[MB]",
+        "Show or set the memory limit for cell programs",
+        |state, call| state.set_mem_limit(call.args),
+    ),
+    Magic::line("%version", "", "Show the kernel and V compiler versions", version),
+    Magic::line(
+        "%vversion",
+        "[path-or-name]",
+        "Show or switch the v binary that compiles cells",
+        |state, call| state.select_v_binary(call.args),
+    ),
+    Magic::line(
+        "%capabilities",
+        "",
+        "Show what the V compiler in use supports",
+        |state, _| ExecOutput::text(state.capabilities.format()),
+    ),
+    Magic::line(
+        "%cc",
+        "[path | default]",
+        "Show or set the C compiler V builds cells with",
+        |state, call| state.select_c_compiler(call.args),
+    ),
+    Magic::line(
+        "%warnings",
+        "[on|off]",
+        "Show or set whether compiler warnings appear in the output",
+        |state, call| state.warnings_magic(call.args),
+    ),
+    Magic::leading(
+        "%nowrap",
+        "",
+        "Run the rest of the cell without wrapping it in fn main()",
+        |state, call| state.run_code(call.body, CellMode::Unwrapped),
+    ),
+    Magic::line("%lsmagic", "", "List the available magics", |state, _| {
+        ExecOutput::text(format_magics(&state.config))
+    }),
+    Magic::leading(
+        "%%c",
+        "",
+        "Show the C code V generates for the cell",
+        |state, call| state.show_c(call.body),
+    ),
+    Magic::leading(
+        "%%writefile",
+        "<module>/<file>.v",
+        "Save the cell as a module source for later imports",
+        |state, call| state.write_module_file(call.args, call.body),
+    ),
+    Magic::leading(
+        "%%timeit",
+        "[-n N] [-r R]",
+        "Time the cell over repeated runs",
+        |state, call| state.timeit(call.args, call.body),
+    ),
+    Magic::leading("%%timeout", "<secs>", "Run the cell with its own time limit", timeout),
+    Magic::leading(
+        "%%raw",
+        "",
+        "Keep the cell as notes; it is never compiled",
+        |_, _| ExecOutput::default(),
+    ),
+    Magic::leading(
+        "%%vsh",
+        "",
+        "Run the cell as a V shell script",
+        |state, call| state.run_code(call.body, CellMode::Script),
+    ),
+];
+
+/// The registry entry for `name` (as written), if there is one.
+pub fn find(name: &str) -> Option<&'static Magic> {
+    MAGICS.iter().find(|m| m.name == name)
+}
+
+/// Run the magic `code` starts with.  `None` when it does not start with
+/// one; an unknown or disabled magic is an error.
+pub fn dispatch(state: &mut KernelState, code: &str) -> Option<ExecOutput> {
+    let name = magic_name(code)?;
+    let Some(magic) = find(name) else {
+        return Some(ExecOutput::error(format!(
+            "Unknown magic `{name}`. Run %lsmagic to list the available magics.\n"
+        )));
+    };
+    if !magic.enabled(&state.config) {
+        return Some(ExecOutput::error(format!("Magic `{name}` is disabled in this kernel.\n")));
+    }
+
+    let code = code.trim_start();
+    let call = if magic.takes_body {
+        let (first, body) = code.split_once('\n').unwrap_or((code, ""));
+        Invocation {
+            args: first[name.len()..].trim(),
+            body,
+        }
+    } else {
+        Invocation {
+            args: code[name.len()..].trim(),
+            body: "",
+        }
+    };
+    if magic.args.is_empty() && !call.args.is_empty() {
+        return Some(ExecOutput::error(format!(
+            "`{name}` takes no arguments.\nUsage: {}\n",
+            magic.usage()
+        )));
+    }
+    Some((magic.run)(state, call))
+}
+
+/// The magic a cell starts with, as written (`%edit`, `%%vsh`), if any.
+fn magic_name(code: &str) -> Option<&str> {
+    let first = code.trim_start().lines().next()?;
+    let name = first.split_whitespace().next()?;
+    let bare = name.trim_start_matches('%');
+    let is_magic = name.starts_with('%')
+        && name.len() - bare.len() <= 2
+        && bare.starts_with(|c: char| c.is_ascii_alphabetic());
+    is_magic.then_some(name)
+}
+
+/// The `%lsmagic` listing: the enabled line magics, then the enabled cell
+/// magics, one per line with its description.
+pub fn format_magics(config: &Config) -> String {
+    let enabled: Vec<&Magic> = MAGICS.iter().filter(|m| m.enabled(config)).collect();
+    let width = enabled.iter().map(|m| m.usage().len()).max().unwrap_or(0);
+
+    let mut out = String::new();
+    for (heading, cell) in [("Line magics", false), ("Cell magics", true)] {
+        out.push_str(&format!("[v-kernel] {heading}:\n"));
+        for m in enabled.iter().filter(|m| m.is_cell_magic() == cell) {
+            out.push_str(&format!("  {:width$}  {}\n", m.usage(), m.description));
+        }
+    }
+    let disabled = MAGICS.len() - enabled.len();
+    if disabled > 0 {
+        out.push_str(&format!("[v-kernel] {disabled} magic(s) disabled in this kernel are not shown.\n"));
+    }
+    out
+}
+
+// ── Handlers ──────────────────────────────────────────────────────────────────
+
+/// `%clear_output`: clear the cell's rendered output, then run the rest of
+/// the cell, if any.  Session state is untouched.
+fn clear_output(state: &mut KernelState, call: Invocation) -> ExecOutput {
+    // When streaming, clear now: clearing at the end would wipe the output
+    // the rest of the cell streams.
+    let cleared = state.send_live("clear_output", json!({ "wait": false }));
+    let mut out = if call.body.trim().is_empty() {
+        ExecOutput::default()
+    } else {
+        state.execute_cell(call.body)
+    };
+    out.clear_output = !cleared;
+    out
+}
+
+/// `%reset`: clear all accumulated declarations and reset the execution
+/// counter to `V_KERNEL_START_COUNT` (0 by default), without invoking V.
+fn reset(state: &mut KernelState, _: Invocation) -> ExecOutput {
+    // Not counting this `%reset` cell itself.
+    let prev_count = state.execution_count - 1;
+    let prev_decls = state.declarations.len();
+    state.declarations.clear();
+    state.edits.clear();
+    state.execution_count = state.config.start_count;
+    ExecOutput::text(format!(
+        "[v-kernel] Session reset.\n\
+         Cleared {prev_decls} accumulated declaration(s). \
+         Execution counter was {prev_count}, now reset to {}.\n",
+        state.execution_count
+    ))
+}
+
+/// `%show`: the complete source that would be prepended to the next cell.
+fn show(state: &mut KernelState, _: Invocation) -> ExecOutput {
+    if state.declarations.is_empty() {
+        return ExecOutput::text("[v-kernel] No declarations accumulated yet.\n");
+    }
+    let source = state.build_source(&[], CellMode::Module, &[]);
+    ExecOutput::text(format!(
+        "[v-kernel] Accumulated source ({} declaration(s)):\n\n{source}",
+        state.declarations.len()
+    ))
+}
+
+/// `%version`: the kernel build and the V compiler version.
+fn version(state: &mut KernelState, _: Invocation) -> ExecOutput {
+    let v = v_version(&state.v_binary).unwrap_or_else(|e| format!("unavailable ({e})"));
+    ExecOutput::text(format!("[v-kernel] v-kernel {}\n[v-kernel] V: {v}\n", kernel_version()))
+}
+
+/// `%%timeout <secs>`: run the rest of the cell with this time limit for its
+/// build and its program instead of `V_KERNEL_EXEC_TIMEOUT_SECS`; `0` means
+/// no limit.
+fn timeout(state: &mut KernelState, call: Invocation) -> ExecOutput {
+    match call.args.parse::<f64>() {
+        Ok(secs) if secs.is_finite() && secs >= 0.0 => {
+            state.timeout = (secs > 0.0).then(|| Duration::from_secs_f64(secs));
+            state.execute_cell(call.body)
+        }
+        _ => ExecOutput::error(format!(
+            "%%timeout needs a number of seconds (0 for no limit), got `{}`.\n",
+            call.args
+        )),
+    }
+}
@@ -10,6 +10,12 @@
 //!   - Control socket: handles shutdown_request, interrupt_request
 //!   - Heartbeat:      echoes back raw bytes to signal liveness
 //!
+//!   Shell, control and stdin are multiplexed with `zmq::poll` on the main
+//!   thread, which owns IOPub as well.  Cells run on a single execution
+//!   worker thread; heartbeat has its own thread.  While a cell runs, shell
+//!   requests wait in their ZeroMQ queue but control (interrupt, shutdown)
+//!   is still served.
+//!
 //! Stateful execution:
 //!   Top-level declarations (fn, struct, enum, const, import, type, interface)
 //!   accumulate across cells — later cells can reference structs and functions
@@ -19,7 +25,7 @@
 //!   cell only and are NOT accumulated.  This means re-running or editing a
 //!   cell never causes "already defined" / redeclaration errors from stale
 //!   earlier runs.  On each execute_request the kernel synthesises a complete
-//!   .v source file and runs it via `v run <tmpfile>`.
+//!   .v source file, compiles it with `v -o <bin> <tmpfile>` and runs the binary.
 //!
 //! Rich dump() output:
 //!   The kernel post-processes stdout to detect V's dump() output format:
@@ -28,17 +34,34 @@
 //!   Jupyter display_data message (MIME type text/html). Non-matching lines
 //!   are forwarded as plain stream output as before.
 
+mod capabilities;
+mod config;
+mod log;
+mod magic;
+mod session;
+
 use chrono::Utc;
+use config::Config;
+use log::log;
 use hmac::{Hmac, Mac};
+use capabilities::VCapabilities;
 use serde::Deserialize;
 use serde_json::{json, Value};
+use session::SharedSession;
 use sha2::Sha256;
 use std::{
+    cell::Cell,
+    collections::{BTreeMap, HashSet},
     env, fs,
-    path::PathBuf,
+    io::Read,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex, MutexGuard, TryLockError,
+    },
     thread,
+    time::{Duration, Instant},
 };
 use uuid::Uuid;
 use zmq::{Context, Socket, SocketType};
@@ -80,7 +103,7 @@ impl JupyterMessage {
         if !key.is_empty() {
             let expected = compute_hmac(key, &[header_raw, parent_raw, metadata_raw, content_raw]);
             if expected != hmac_sig {
-                eprintln!("[v-kernel] HMAC mismatch — dropping message");
+                log!("HMAC mismatch — dropping message");
                 return None;
             }
         }
@@ -132,11 +155,11 @@ fn compute_hmac(key: &[u8], parts: &[&[u8]]) -> String {
 }
 
 /// Build a reply header for a given message type.
-fn make_header(msg_type: &str, session: &str) -> Value {
+fn make_header(msg_type: &str, session: &str, username: &str) -> Value {
     json!({
         "msg_id": Uuid::new_v4().to_string(),
         "session": session,
-        "username": "v-kernel",
+        "username": username,
         "date": Utc::now().to_rfc3339(),
         "msg_type": msg_type,
         "version": "5.3"
@@ -144,28 +167,52 @@ fn make_header(msg_type: &str, session: &str) -> Value {
 }
 
 /// Send a message on a socket.
-fn send_message(socket: &Socket, msg: &JupyterMessage, key: &[u8]) {
+///
+/// Stops at the first frame that fails to send and returns that error.
+fn send_message(socket: &Socket, msg: &JupyterMessage, key: &[u8]) -> zmq::Result<()> {
     let frames = msg.to_frames(key);
     for (i, frame) in frames.iter().enumerate() {
         let is_last = i == frames.len() - 1;
         if is_last {
-            socket.send(frame, 0).ok();
+            socket.send(frame, 0)?;
         } else {
-            socket.send(frame, zmq::SNDMORE).ok();
+            socket.send(frame, zmq::SNDMORE)?;
         }
     }
+    Ok(())
 }
 
 /// Receive a multipart message from a socket.
-fn recv_message(socket: &Socket, key: &[u8]) -> Option<JupyterMessage> {
+///
+/// Messages larger than `config.max_message_bytes` in total, or with more
+/// than `config.max_message_frames` frames, are read to the end and
+/// dropped with a log line instead of being kept in memory.
+fn recv_message(socket: &Socket, key: &[u8], config: &Config) -> Option<JupyterMessage> {
     let mut frames = Vec::new();
+    let mut total = 0usize;
+    let mut oversized = false;
     loop {
         let frame = socket.recv_bytes(0).ok()?;
-        frames.push(frame);
+        total += frame.len();
+        if total > config.max_message_bytes || frames.len() >= config.max_message_frames {
+            oversized = true;
+            frames.clear();
+        }
+        if !oversized {
+            frames.push(frame);
+        }
         if !socket.get_rcvmore().unwrap_or(false) {
             break;
         }
     }
+    if oversized {
+        log!(
+            "Dropping oversized message ({total} bytes; limits are {} bytes, {} frames)",
+            config.max_message_bytes,
+            config.max_message_frames
+        );
+        return None;
+    }
     JupyterMessage::from_frames(frames, key)
 }
 
@@ -196,8 +243,140 @@ impl ConnectionInfo {
 
 // ── Session state ─────────────────────────────────────────────────────────────
 
-/// Accumulated kernel state across cells.
+/// How a cell's statements are placed in the synthesised source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CellMode {
+    /// `module main` with statements wrapped in a synthesised `fn main()`.
+    Module,
+    /// `%%vsh`: a V shell script with statements left at top level.
+    Script,
+    /// `%nowrap`: `module main` with statements left at top level, as
+    /// written, for V to run without a synthesised `fn main()`.
+    Unwrapped,
+}
+
+impl CellMode {
+    /// File extension V uses to pick the compilation mode.
+    fn extension(self) -> &'static str {
+        match self {
+            CellMode::Module | CellMode::Unwrapped => "v",
+            CellMode::Script => "vsh",
+        }
+    }
+}
+
+/// The outcome of executing one cell.
 #[derive(Debug, Default)]
+struct ExecOutput {
+    /// What the program (or a magic) printed to stdout.
+    stdout: String,
+    /// What the program printed to stderr — or the error text when `error`
+    /// is set.
+    stderr: String,
+    /// Compiler warnings/notes from a build that succeeded.  Kept apart from
+    /// the program's own output so the two streams are never interleaved.
+    diagnostics: String,
+    /// How the cell failed, if it did.
+    error: Option<ErrorKind>,
+    /// `execute_reply` payloads, e.g. the `edit` payload from `%edit`.
+    payload: Vec<Value>,
+    /// Exit code of the cell's program, if it was built and ran to exit.
+    exit_code: Option<i32>,
+    /// Time spent in `v -o <bin>`, if the cell was compiled.
+    compile_time: Option<Duration>,
+    /// Time spent running the compiled binary, if it ran.
+    run_time: Option<Duration>,
+    /// Ask the front-end to clear the cell's existing output first.
+    clear_output: bool,
+    /// The time limit that applied to each process the cell ran.
+    timeout: Option<Duration>,
+    /// `diagnostics` were already published live (streaming mode).
+    diagnostics_sent: bool,
+}
+
+impl ExecOutput {
+    /// Plain informational output, e.g. from a magic command.
+    fn text(stdout: impl Into<String>) -> Self {
+        ExecOutput {
+            stdout: stdout.into(),
+            ..Default::default()
+        }
+    }
+
+    /// A failure of the kernel itself (bad magic arguments, V missing, a
+    /// file it could not write) with the given message as stderr.
+    fn error(stderr: impl Into<String>) -> Self {
+        ExecOutput::failed(ErrorKind::Internal, stderr)
+    }
+
+    /// A failure of the given kind with the given message as stderr.
+    fn failed(kind: ErrorKind, stderr: impl Into<String>) -> Self {
+        ExecOutput {
+            stderr: stderr.into(),
+            error: Some(kind),
+            ..Default::default()
+        }
+    }
+
+    fn is_error(&self) -> bool {
+        self.error.is_some()
+    }
+}
+
+/// Why a cell failed.  Each failure maps to exactly one kind, which becomes
+/// the `ename` of its `error` message and `execute_reply`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorKind {
+    /// V rejected the cell (including the kernel's own bracket pre-check).
+    CompileError,
+    /// The program exited with a non-zero status.
+    RuntimeError,
+    /// The program panicked (`V panic:` on stderr).
+    VPanic,
+    /// An `assert` failed.
+    AssertionError,
+    /// The compiler or program ran past the `%timeout` limit.
+    Timeout,
+    /// The compiler or program was stopped by an interrupt request.
+    Interrupted,
+    /// The program could not allocate within the `%memlimit` limit.
+    MemoryLimit,
+    /// The kernel itself could not carry out the cell.
+    Internal,
+}
+
+impl ErrorKind {
+    /// The `ename` reported to the front-end.
+    fn ename(self) -> &'static str {
+        match self {
+            ErrorKind::CompileError => "CompileError",
+            ErrorKind::RuntimeError => "RuntimeError",
+            ErrorKind::VPanic => "VPanic",
+            ErrorKind::AssertionError => "AssertionError",
+            ErrorKind::Timeout => "Timeout",
+            ErrorKind::Interrupted => "Interrupted",
+            ErrorKind::MemoryLimit => "MemoryLimit",
+            ErrorKind::Internal => "Internal",
+        }
+    }
+
+    /// The `evalue` reported when there is nothing more specific to say.
+    fn description(self) -> &'static str {
+        match self {
+            ErrorKind::CompileError => "V compilation error",
+            ErrorKind::RuntimeError => "The program exited with an error",
+            ErrorKind::VPanic => "The program panicked",
+            ErrorKind::AssertionError => "Assertion failed",
+            ErrorKind::Timeout => "Time limit exceeded",
+            ErrorKind::Interrupted => "Interrupted",
+            ErrorKind::MemoryLimit => "Memory limit exceeded",
+            ErrorKind::Internal => "The kernel could not run the cell",
+        }
+    }
+}
+
+/// Accumulated kernel state across cells.
+#[derive(Debug)]
 struct KernelState {
     /// Top-level declarations seen so far (fn, struct, enum, …).
     /// These accumulate across cells — later cells can use earlier structs/fns.
@@ -206,31 +385,132 @@ struct KernelState {
     execution_count: u32,
     /// Temporary directory for compiled artefacts
     tmp_dir: PathBuf,
-    /// PID of the currently running `v run` child process, if any.
-    running_pid: Option<u32>,
+    /// PID of the currently running `v` compiler or compiled cell process, if any.
+    ///
+    /// Kept behind its own lock, shared with `Kernel`, because the execution
+    /// worker holds the state lock for the whole duration of a cell — an
+    /// `interrupt_request` or restart handled on the socket thread must not
+    /// have to wait for the cell to finish.
+    running_pid: Arc<Mutex<Option<u32>>>,
+    /// When the last shell or control message arrived (or the last cell
+    /// finished).  Read by the idle watchdog.
+    last_activity: Instant,
+    /// Declarations opened with `%edit`, synced back before every cell.
+    edits: Vec<PendingEdit>,
+    /// Declarations from cells tagged `init`, re-applied after a restart.
+    init_declarations: Vec<String>,
+    /// The `v` executable cells are compiled with; changed by `%vversion`.
+    v_binary: PathBuf,
+    /// The shared session this kernel joined (`V_KERNEL_SESSION`), if any.
+    shared: Option<SharedSession>,
+    /// Extra environment for cell programs (not the compiler), set by `%env`.
+    program_env: BTreeMap<String, String>,
+    /// Time limit for each process of the running cell: the configured
+    /// default, or the cell's `%%timeout`.
+    timeout: Option<Duration>,
+    /// Where output published while a cell runs goes, when streaming is on
+    /// (`V_KERNEL_STREAM_WINDOW_MS`).  The event loop publishes it.
+    live: Option<mpsc::Sender<LiveOutput>>,
+    /// Memory limit for cell programs in MB: `V_KERNEL_MEM_LIMIT_MB`, or
+    /// what `%memlimit` set.
+    mem_limit_mb: Option<u64>,
+    /// C compiler passed to `v` as `-cc`: `V_KERNEL_CC`, or what `%cc` set.
+    c_compiler: Option<PathBuf>,
+    /// Whether compiler warnings are shown: `V_KERNEL_SHOW_WARNINGS`, or
+    /// what `%warnings` set.
+    show_warnings: bool,
+    /// What `v_binary` supports, probed when it was chosen.
+    capabilities: VCapabilities,
+    config: Config,
+}
+
+/// A declaration written out by `%edit` for the front-end to open.
+#[derive(Debug)]
+struct PendingEdit {
+    /// Declaration name, used to find it again in `declarations`.
+    name: String,
+    path: PathBuf,
+    /// File contents as last written or synced, to detect changes.
+    contents: String,
 }
 
 impl KernelState {
-    fn new() -> Self {
-        let tmp_dir = env::temp_dir().join(format!("v-kernel-{}", Uuid::new_v4()));
+    /// Start a session whose temporary directory is a new one under `base`.
+    fn new(config: Config, base: &Path) -> Self {
+        let tmp_dir = base.join(format!("v-kernel-{}", Uuid::new_v4()));
         fs::create_dir_all(&tmp_dir).ok();
+        log!("Cell sources are written to {}", tmp_dir.display());
+        // Make `import vkernel` available to every cell.
+        let helper_dir = tmp_dir.join("vkernel");
+        fs::create_dir_all(&helper_dir).ok();
+        fs::write(helper_dir.join("vkernel.v"), VKERNEL_MODULE).ok();
+        let shared = config.session.as_deref().and_then(|name| {
+            SharedSession::open(name)
+                .inspect_err(|e| log!("Cannot join shared session {name:?}, running isolated: {e}"))
+                .ok()
+        });
+        let capabilities = probe_capabilities(&config.v_binary, &tmp_dir);
         KernelState {
             declarations: Vec::new(),
-            execution_count: 0,
+            execution_count: config.start_count,
             tmp_dir,
-            running_pid: None,
+            running_pid: Arc::new(Mutex::new(None)),
+            last_activity: Instant::now(),
+            edits: Vec::new(),
+            init_declarations: Vec::new(),
+            v_binary: config.v_binary.clone(),
+            shared,
+            program_env: BTreeMap::new(),
+            timeout: config.exec_timeout,
+            live: None,
+            mem_limit_mb: config.mem_limit_mb,
+            c_compiler: config.c_compiler.clone(),
+            show_warnings: config.show_warnings,
+            capabilities,
+            config,
+        }
+    }
+
+    /// Reset the session for a `shutdown_request` with `restart: true`,
+    /// keeping only the declarations of `init`-tagged cells.  Returns how
+    /// many were re-applied.
+    fn restart(&mut self) -> usize {
+        self.declarations = self.init_declarations.clone();
+        self.execution_count = self.config.start_count;
+        self.edits.clear();
+        if self.v_binary != self.config.v_binary {
+            self.v_binary = self.config.v_binary.clone();
+            self.capabilities = probe_capabilities(&self.v_binary, &self.tmp_dir);
+        }
+        self.program_env.clear();
+        self.mem_limit_mb = self.config.mem_limit_mb;
+        self.c_compiler = self.config.c_compiler.clone();
+        self.show_warnings = self.config.show_warnings;
+        if let Some(shared) = &mut self.shared {
+            shared.rejoin();
+        }
+        self.declarations.len()
+    }
+
+    /// Record activity so the idle watchdog does not fire.
+    fn touch(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    /// Publish an IOPub message right away, while the cell is still running.
+    /// Returns false when streaming is off; the caller then reports the
+    /// output with the rest of the cell's result instead.
+    fn send_live(&self, msg_type: &'static str, content: Value) -> bool {
+        match &self.live {
+            Some(live) => live.send(LiveOutput { msg_type, content }).is_ok(),
+            None => false,
         }
     }
 
     /// Classify and accumulate a cell, then run it.
     ///
-    /// Magic commands:
-    ///   %reset  — clear all accumulated declarations and reset the execution
-    ///             counter to 0. Returns a confirmation message and does NOT
-    ///             invoke the V compiler.
-    ///   %show   — print the complete synthesised V source file that would be
-    ///             prepended to the next cell. Useful for inspecting accumulated
-    ///             state. Returns the source as plain stream output.
+    /// A cell starting with a magic (`%name`, `%%name`) is handled by the
+    /// magic registry instead; see `magic::MAGICS` for the list.
     ///
     /// Declarations (fn, struct, enum, …) are accumulated across cells so
     /// later cells can reference earlier definitions.
@@ -240,69 +520,634 @@ impl KernelState {
     /// means re-running or editing a cell never causes "already defined"
     /// errors from stale earlier runs.
     ///
-    /// Returns (stdout, stderr, is_error).
-    fn execute(&mut self, code: &str) -> (String, String, bool) {
-        let trimmed = code.trim();
-
-        // ── %reset ────────────────────────────────────────────────────────────
-        if trimmed == "%reset" {
-            let prev_count = self.execution_count;
-            let prev_decls = self.declarations.len();
-            self.declarations.clear();
-            self.execution_count = 0;
-            let msg = format!(
-                "[v-kernel] Session reset.\n\
-                 Cleared {prev_decls} accumulated declaration(s). \
-                 Execution counter was {prev_count}, now reset to 0.\n"
-            );
-            return (msg, String::new(), false);
+    fn execute(&mut self, code: &str) -> ExecOutput {
+        // Every execution counts, magic-only cells included, so the number
+        // matches the one announced in `execute_input`.
+        self.execution_count += 1;
+        self.timeout = self.config.exec_timeout;
+        let mut notes = self.pull_shared();
+        notes.push_str(&self.sync_edits());
+        if !notes.is_empty() && self.send_live("stream", json!({ "name": "stdout", "text": notes })) {
+            notes.clear();
         }
+        let mut out = self.execute_cell(code);
+        self.push_shared();
+        out.stdout.insert_str(0, &notes);
+        out.timeout = self.timeout;
+        out
+    }
 
-        // ── %show ─────────────────────────────────────────────────────────────
-        if trimmed == "%show" {
-            let source = self.build_source(&[]);
-            let out = if self.declarations.is_empty() {
-                "[v-kernel] No declarations accumulated yet.\n".to_string()
-            } else {
-                format!("[v-kernel] Accumulated source ({} declaration(s)):\n\n{source}",
-                    self.declarations.len())
-            };
-            return (out, String::new(), false);
+    /// Take the shared session's declarations if another kernel changed
+    /// them, returning a note when it did.
+    fn pull_shared(&mut self) -> String {
+        let Some(shared) = &mut self.shared else {
+            return String::new();
+        };
+        match shared.pull() {
+            Ok(Some(declarations)) => {
+                self.declarations = declarations;
+                format!(
+                    "[v-kernel] Loaded {} declaration(s) from shared session `{}`.\n",
+                    self.declarations.len(),
+                    shared.name()
+                )
+            }
+            Ok(None) => String::new(),
+            Err(e) => {
+                log!("Cannot read shared session {:?}: {e}", shared.name());
+                String::new()
+            }
         }
+    }
 
-        self.execution_count += 1;
+    /// Publish this kernel's declarations to the shared session.
+    fn push_shared(&mut self) {
+        let Some(shared) = &mut self.shared else {
+            return;
+        };
+        match shared.push(&self.declarations) {
+            Ok(declarations) => self.declarations = declarations,
+            Err(e) => log!("Cannot write shared session {:?}: {e}", shared.name()),
+        }
+    }
 
-        let (new_decls, cell_stmts) = classify(code);
+    /// Run a cell: its magic, if it starts with one, else its V code.
+    fn execute_cell(&mut self, code: &str) -> ExecOutput {
+        match magic::dispatch(self, code) {
+            Some(out) => out,
+            None => self.run_code(code, CellMode::Module),
+        }
+    }
+
+    /// Classify `code`, accumulate its declarations and build and run it in
+    /// `mode`.
+    fn run_code(&mut self, code: &str, mode: CellMode) -> ExecOutput {
+        // A magic line with nothing after it: don't compile an empty program.
+        if code.trim().is_empty() {
+            return ExecOutput::default();
+        }
+
+        let (mut new_decls, mut cell_stmts) = classify(code);
+
+        // A cell with a `fn main` of its own must not add it to the session,
+        // where it would clash with every later cell's.  Normally its body
+        // (then any loose statements) becomes the synthesised main; unwrapped,
+        // it is compiled as written, this once.
+        let mut main_note = String::new();
+        if let Some(i) = new_decls.iter().position(|d| main_body(d).is_some()) {
+            let main = new_decls.remove(i);
+            match (mode, main_body(&main)) {
+                (CellMode::Module, Some(body)) => {
+                    let loose = std::mem::replace(&mut cell_stmts, classify(body).1);
+                    cell_stmts.extend(loose);
+                    main_note = MAIN_MERGED_NOTE.to_string();
+                }
+                _ => cell_stmts.insert(0, main),
+            }
+        }
+        // Say so before the build, whose errors would otherwise point at
+        // lines of a `main` the user did not write.
+        if !main_note.is_empty() && self.send_live("stream", json!({ "name": "stderr", "text": main_note })) {
+            main_note.clear();
+        }
+
+        // A lone statement that uses nothing from the session does not need
+        // the accumulated program around it.
+        let standalone = mode == CellMode::Module
+            && new_decls.is_empty()
+            && cell_stmts.len() == 1
+            && !references_session(&self.declarations, &cell_stmts);
 
         // Accumulate only declarations.
         self.declarations.extend(new_decls);
 
-        // Build the full source file for this cell.
-        let source = self.build_source(&cell_stmts);
+        // `return <expr>` at cell scope would be a type error inside the
+        // generated `fn main()`; show the value as the cell's result instead
+        // and keep the early exit.
+        let mut extra_imports = Vec::new();
+        let last = cell_stmts.len().saturating_sub(1);
+        let mut echoed = Vec::with_capacity(cell_stmts.len());
+        for (i, stmt) in cell_stmts.into_iter().enumerate() {
+            match top_level_return(&stmt) {
+                Some(expr) => {
+                    echoed.push(format!("vkernel.echo('', {expr})"));
+                    if i != last {
+                        echoed.push("return".to_string());
+                    }
+                    extra_imports.push("import vkernel");
+                }
+                None => echoed.push(stmt),
+            }
+        }
+        let mut cell_stmts = echoed;
+
+        // Echo the value of a trailing assignment, if enabled.
+        if self.config.echo_assignments {
+            let targets = cell_stmts.last().map(|s| assignment_targets(s)).unwrap_or_default();
+            if !targets.is_empty() {
+                let labelled = targets.len() > 1;
+                for target in targets {
+                    let label = if labelled { target.as_str() } else { "" };
+                    cell_stmts.push(format!("vkernel.echo('{label}', {target})"));
+                }
+                extra_imports.push("import vkernel");
+            }
+        }
+
+        // Build the full source file for this cell, write it to a temp file,
+        // then compile it with `v -o <bin> <file>` and run the binary.
+        let build_and_run = |state: &mut Self| {
+            let source = if standalone {
+                state.build_standalone_source(&cell_stmts, &extra_imports)
+            } else {
+                state.build_source(&cell_stmts, mode, &extra_imports)
+            };
+            let name = source_file_name(&state.config.source_name, state.execution_count, &source);
+            let src_path = state.tmp_dir.join(format!("{name}.{}", mode.extension()));
+            if let Err(e) = fs::write(&src_path, &source) {
+                return ExecOutput::error(format!("Failed to write source: {e}"));
+            }
+            run_v(&src_path, state)
+        };
+        let mut out = build_and_run(self);
+
+        // An unused import is a build error in V, and once accumulated it
+        // would fail every cell after it.  Drop it and try once more.
+        if self.config.drop_unused_imports && out.run_time.is_none() {
+            let dropped = self.drop_imports(&unused_imports(&out.stderr));
+            if !dropped.is_empty() {
+                out = build_and_run(self);
+                let note: String = dropped
+                    .iter()
+                    .map(|d| format!("[v-kernel] Removed unused `{d}` from the session.\n"))
+                    .collect();
+                out.stdout.insert_str(0, &note);
+            }
+        }
+        out.diagnostics.insert_str(0, &main_note);
+        out
+    }
+
+    /// Remove the accumulated imports of `modules` (by path or alias),
+    /// returning the import lines removed.
+    fn drop_imports(&mut self, modules: &[&str]) -> Vec<String> {
+        let mut dropped = Vec::new();
+        self.declarations.retain(|d| {
+            let Some(import) = d.trim().strip_prefix("import ") else {
+                return true;
+            };
+            let (path, alias) = match import.split_once(" as ") {
+                Some((path, alias)) => (path.trim(), alias.trim()),
+                None => (import.trim(), import.trim().rsplit('.').next().unwrap_or("")),
+            };
+            if modules.iter().any(|m| *m == path || *m == alias) {
+                dropped.push(d.trim().to_string());
+                false
+            } else {
+                true
+            }
+        });
+        dropped
+    }
+
+    /// Compile `code` against the session to C and return the C source.
+    /// Nothing from `code` is accumulated.
+    fn show_c(&mut self, code: &str) -> ExecOutput {
+        let (new_decls, cell_stmts) = classify(code);
+        let saved = self.declarations.len();
+        self.declarations.extend(new_decls);
+        let source = self.build_source(&cell_stmts, CellMode::Module, &[]);
+        self.declarations.truncate(saved);
 
-        // Write to a temp file.
-        let src_path = self.tmp_dir.join(format!("cell_{}.v", self.execution_count));
+        let src_path = self.tmp_dir.join("cell_c.v");
         if let Err(e) = fs::write(&src_path, &source) {
-            return (String::new(), format!("Failed to write source: {e}"), true);
+            return ExecOutput::error(format!("Failed to write source: {e}"));
         }
+        generate_c(&src_path, self)
+    }
 
-        // Run with `v run <file>`
+    /// Evaluate `expr` against the accumulated declarations and return its
+    /// value as the cell's result.
+    ///
+    /// Cell statements are not accumulated, so local `x := …` bindings from
+    /// earlier cells do not exist here — only declarations (`const`,
+    /// `__global`, functions, …) do.
+    fn print_value(&mut self, expr: &str) -> ExecOutput {
+        let stmts = [format!("vkernel.echo('', {expr})")];
+        let source = if references_session(&self.declarations, &stmts) {
+            self.build_source(&stmts, CellMode::Module, &["import vkernel"])
+        } else {
+            self.build_standalone_source(&stmts, &["import vkernel"])
+        };
+        let src_path = self.tmp_dir.join("cell_p.v");
+        if let Err(e) = fs::write(&src_path, &source) {
+            return ExecOutput::error(format!("Failed to write source: {e}"));
+        }
         run_v(&src_path, self)
     }
 
+    /// Write `body` to `<tmp_dir>/<path>`.  Cell sources live in `tmp_dir`,
+    /// so V finds modules in its subdirectories without any extra flags.
+    fn write_module_file(&mut self, path: &str, body: &str) -> ExecOutput {
+        let rel = Path::new(path);
+        let components: Vec<&str> = rel
+            .components()
+            .map(|c| match c {
+                std::path::Component::Normal(part) => part.to_str().unwrap_or(""),
+                _ => "",
+            })
+            .collect();
+        let valid = components.len() >= 2
+            && components.iter().all(|c| !c.is_empty())
+            && rel.extension().is_some_and(|e| e == "v");
+        if !valid {
+            return ExecOutput::error(
+                "Usage: %%writefile <module>/<file>.v — a relative path inside a module directory\n",
+            );
+        }
+        if components[0] == "vkernel" {
+            return ExecOutput::error("The vkernel module is provided by the kernel and cannot be replaced.\n");
+        }
+
+        // V requires every file of a module to declare it; add the line when
+        // the cell leaves it out.
+        let module = components[components.len() - 2];
+        let has_module_line = body
+            .lines()
+            .map(str::trim)
+            .find(|l| !l.is_empty() && !l.starts_with("//"))
+            .is_some_and(|l| l.starts_with("module "));
+        let contents = if has_module_line {
+            body.to_string()
+        } else {
+            format!("module {module}\n\n{body}")
+        };
+
+        let dest = self.tmp_dir.join(rel);
+        if let Some(dir) = dest.parent() {
+            if let Err(e) = fs::create_dir_all(dir) {
+                return ExecOutput::error(format!("Failed to create {}: {e}\n", dir.display()));
+            }
+        }
+        if let Err(e) = fs::write(&dest, &contents) {
+            return ExecOutput::error(format!("Failed to write {path}: {e}\n"));
+        }
+        let import = components[..components.len() - 1].join(".");
+        ExecOutput::text(format!(
+            "[v-kernel] Wrote {path} ({} bytes). Use it with `import {import}`.\n",
+            contents.len()
+        ))
+    }
+
+    /// Benchmark `code` the way IPython's `%%timeit` does.
+    fn timeit(&mut self, args: &str, code: &str) -> ExecOutput {
+        let (loops, rounds) = match parse_timeit_args(args) {
+            Ok(counts) => counts,
+            Err(e) => return ExecOutput::error(format!("{e}\nUsage: %%timeit [-n N] [-r R]\n")),
+        };
+
+        let (new_decls, body) = classify(code);
+        let mut stmts = vec![
+            "mut vkernel_times := []f64{}".to_string(),
+            format!("for _ in 0 .. {rounds} {{"),
+            "\tvkernel_sw := time.new_stopwatch()".to_string(),
+            format!("\tfor _ in 0 .. {loops} {{"),
+        ];
+        for stmt in &body {
+            stmts.extend(stmt.lines().map(|l| format!("\t\t{l}")));
+        }
+        stmts.extend([
+            "\t}".to_string(),
+            format!("\tvkernel_times << f64(vkernel_sw.elapsed().nanoseconds()) / {loops}"),
+            "}".to_string(),
+            format!("println('{TIMEIT_MAGIC}{loops} ${{vkernel_times}}')"),
+        ]);
+
+        let saved = self.declarations.len();
+        self.declarations.extend(new_decls);
+        let source = self.build_source(&stmts, CellMode::Module, &["import time"]);
+        self.declarations.truncate(saved);
+
+        let src_path = self.tmp_dir.join("cell_timeit.v");
+        if let Err(e) = fs::write(&src_path, &source) {
+            return ExecOutput::error(format!("Failed to write source: {e}"));
+        }
+        let mut out = run_v(&src_path, self);
+        out.stdout = format_timeit_output(&out.stdout);
+        out
+    }
+
+    /// Report the memory limit for cell programs, or set it to `arg` MB.
+    fn set_mem_limit(&mut self, arg: &str) -> ExecOutput {
+        if arg.is_empty() {
+            return ExecOutput::text(match self.mem_limit_mb {
+                Some(mb) => format!("[v-kernel] Cell programs are limited to {mb} MB.\n"),
+                None => "[v-kernel] Cell programs have no memory limit.\n".to_string(),
+            });
+        }
+        match arg.strip_suffix("MB").unwrap_or(arg).trim().parse::<u64>() {
+            Ok(0) => {
+                self.mem_limit_mb = None;
+                ExecOutput::text("[v-kernel] Memory limit removed.\n")
+            }
+            Ok(mb) => {
+                self.mem_limit_mb = Some(mb);
+                ExecOutput::text(format!("[v-kernel] Cell programs are now limited to {mb} MB.\n"))
+            }
+            Err(_) => ExecOutput::error(format!(
+                "%memlimit needs a number of megabytes (0 for no limit), got `{arg}`.\n"
+            )),
+        }
+    }
+
+    /// Report the current `v` binary, or switch to `choice` if it runs.
+    fn select_v_binary(&mut self, choice: &str) -> ExecOutput {
+        let bin = if choice.is_empty() {
+            self.v_binary.clone()
+        } else {
+            PathBuf::from(choice)
+        };
+        match v_version(&bin) {
+            Ok(version) => {
+                let msg = if choice.is_empty() {
+                    format!("[v-kernel] Using {}: {version}\n", bin.display())
+                } else {
+                    format!("[v-kernel] Switched to {}: {version}\n", bin.display())
+                };
+                self.capabilities = probe_capabilities(&bin, &self.tmp_dir);
+                self.v_binary = bin;
+                ExecOutput::text(msg)
+            }
+            Err(e) => ExecOutput::error(format!(
+                "Cannot use {} as the V compiler: {e}\nStill using {}.\n",
+                bin.display(),
+                self.v_binary.display()
+            )),
+        }
+    }
+
+    /// Report the C compiler `v` builds with, or switch to the one at
+    /// `choice`; `default` goes back to letting `v` choose.
+    fn select_c_compiler(&mut self, choice: &str) -> ExecOutput {
+        match choice {
+            "" => ExecOutput::text(match &self.c_compiler {
+                Some(cc) => format!("[v-kernel] Cells are built with -cc {}.\n", cc.display()),
+                None => "[v-kernel] Cells are built with V's default C compiler.\n".to_string(),
+            }),
+            "default" => {
+                self.c_compiler = None;
+                ExecOutput::text("[v-kernel] Cells are now built with V's default C compiler.\n")
+            }
+            path => {
+                let cc = PathBuf::from(path);
+                if !cc.is_file() {
+                    return ExecOutput::error(format!(
+                        "No C compiler at {path}: give the full path to an existing file.\n"
+                    ));
+                }
+                let msg = format!("[v-kernel] Cells are now built with -cc {}.\n", cc.display());
+                self.c_compiler = Some(cc);
+                ExecOutput::text(msg)
+            }
+        }
+    }
+
+    /// `%warnings`: report whether compiler warnings are shown, or turn
+    /// them `on` or `off` for the session.
+    fn warnings_magic(&mut self, arg: &str) -> ExecOutput {
+        let show = match arg {
+            "" => self.show_warnings,
+            "on" => true,
+            "off" => false,
+            _ => return ExecOutput::error(format!("Usage: %warnings [on|off], got `{arg}`.\n")),
+        };
+        let verb = if arg.is_empty() { "are" } else { "are now" };
+        self.show_warnings = show;
+        ExecOutput::text(if show {
+            format!("[v-kernel] Compiler warnings {verb} shown.\n")
+        } else {
+            format!("[v-kernel] Compiler warnings {verb} hidden (v -w).\n")
+        })
+    }
+
+    /// A `v` command with the session's build flags (`-cc`, `-w`) already
+    /// added.
+    fn v_command(&self) -> Command {
+        let mut cmd = Command::new(&self.v_binary);
+        if let Some(cc) = &self.c_compiler {
+            cmd.arg("-cc").arg(cc);
+        }
+        if !self.show_warnings {
+            cmd.arg("-w");
+        }
+        cmd
+    }
+
+    /// `%env`: list the program environment, show one variable, or set one.
+    fn env_magic(&mut self, args: &str) -> ExecOutput {
+        match args.split_once(char::is_whitespace) {
+            Some((key, value)) => self.set_program_env(key, value.trim_start()),
+            None if args.is_empty() => {
+                if self.program_env.is_empty() {
+                    return ExecOutput::text("[v-kernel] No program environment variables set.\n");
+                }
+                let list: String = self
+                    .program_env
+                    .iter()
+                    .map(|(k, v)| format!("{k}={v}\n"))
+                    .collect();
+                ExecOutput::text(list)
+            }
+            None => match self.program_env.get(args) {
+                Some(value) => ExecOutput::text(format!("{args}={value}\n")),
+                None => ExecOutput::error(format!("`{args}` is not set with %env.\n")),
+            },
+        }
+    }
+
+    fn set_program_env(&mut self, key: &str, value: &str) -> ExecOutput {
+        if key.is_empty() || key.contains(['=', '\0']) || value.contains('\0') {
+            return ExecOutput::error(format!("Invalid environment variable `{key}`.\n"));
+        }
+        self.program_env.insert(key.to_string(), value.to_string());
+        ExecOutput::text(format!("[v-kernel] {key} set for cell programs.\n"))
+    }
+
+    /// Merge the declarations of the V file at `path` into the session and
+    /// run its statements.  A declaration replaces an accumulated one with
+    /// the same name and kind; exact duplicates are skipped.
+    fn import_session(&mut self, path: &str) -> ExecOutput {
+        if path.is_empty() {
+            return ExecOutput::error("Usage: %import_session <file.v>\n");
+        }
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => return ExecOutput::error(format!("Cannot read {path}: {e}\n")),
+        };
+
+        let (decls, mut stmts) = classify(&source);
+        let (mut added, mut replaced, mut unchanged) = (0, 0, 0);
+        for decl in decls {
+            // A saved program's `fn main` holds statements, not a declaration.
+            if let Some(body) = main_body(&decl) {
+                stmts.extend(classify(body).1);
+                continue;
+            }
+            if self.declarations.iter().any(|d| d.trim() == decl.trim()) {
+                unchanged += 1;
+                continue;
+            }
+            let key = decl_key(&decl);
+            match self
+                .declarations
+                .iter_mut()
+                .find(|d| key.is_some() && decl_key(d) == key)
+            {
+                Some(existing) => {
+                    *existing = decl;
+                    replaced += 1;
+                }
+                None => {
+                    self.declarations.push(decl);
+                    added += 1;
+                }
+            }
+        }
+
+        let mut report = format!(
+            "[v-kernel] Imported {path}: {added} new declaration(s), {replaced} replaced, \
+             {unchanged} already present, {} statement(s).\n",
+            stmts.len()
+        );
+        if stmts.is_empty() {
+            return ExecOutput::text(report);
+        }
+        let mut out = self.execute_cell(&stmts.join("\n"));
+        report.push_str(&out.stdout);
+        out.stdout = report;
+        out
+    }
+
+    /// Write declaration `name` to a file and ask the front-end to open it.
+    fn open_edit(&mut self, name: &str) -> ExecOutput {
+        if name.is_empty() {
+            return ExecOutput::error("Usage: %edit <name>\n");
+        }
+        let Some(decl) = self
+            .declarations
+            .iter()
+            .rev()
+            .find(|d| decl_name(d) == Some(name))
+        else {
+            return ExecOutput::error(format!("No accumulated declaration named `{name}`.\n"));
+        };
+
+        let path = self.tmp_dir.join(format!("edit_{name}.v"));
+        let contents = format!("{decl}\n");
+        if let Err(e) = fs::write(&path, &contents) {
+            return ExecOutput::error(format!("Failed to write {}: {e}\n", path.display()));
+        }
+        self.edits.retain(|e| e.name != name);
+        self.edits.push(PendingEdit {
+            name: name.to_string(),
+            path: path.clone(),
+            contents,
+        });
+
+        ExecOutput {
+            stdout: format!(
+                "[v-kernel] Editing `{name}` in {}. Saved changes apply on the next run.\n",
+                path.display()
+            ),
+            payload: vec![json!({
+                "source": "edit_magic",
+                "filename": path.to_string_lossy(),
+                "line_number": 1
+            })],
+            ..Default::default()
+        }
+    }
+
+    /// Replace declarations whose `%edit` file changed since it was last
+    /// read, returning a note for each one updated.
+    fn sync_edits(&mut self) -> String {
+        let mut notes = String::new();
+        for edit in &mut self.edits {
+            let Ok(contents) = fs::read_to_string(&edit.path) else {
+                continue;
+            };
+            if contents == edit.contents {
+                continue;
+            }
+            let Some(decl) = self
+                .declarations
+                .iter_mut()
+                .rev()
+                .find(|d| decl_name(d) == Some(edit.name.as_str()))
+            else {
+                continue;
+            };
+            *decl = contents.trim_end().to_string();
+            edit.contents = contents;
+            notes.push_str(&format!("[v-kernel] Updated `{}` from {}.\n", edit.name, edit.path.display()));
+        }
+        notes
+    }
+
+    /// Like `build_source`, but keeping only the session imports the
+    /// statements use: for statements that `references_session` found
+    /// independent of the rest, so quick calculations do not recompile every
+    /// accumulated declaration.  Unused imports would only add warnings.
+    fn build_standalone_source(&mut self, cell_stmts: &[String], extra_imports: &[&str]) -> String {
+        let idents = identifiers(cell_stmts);
+        let saved = std::mem::take(&mut self.declarations);
+        self.declarations = saved
+            .iter()
+            .filter(|d| {
+                let Some(import) = d.trim().strip_prefix("import ") else {
+                    return false;
+                };
+                // `import os { getenv }` brings in names we don't track.
+                import.contains('{') || {
+                    let alias = import.split(" as ").nth(1).unwrap_or(import);
+                    let name = alias.trim().rsplit('.').next().unwrap_or("");
+                    idents.contains(name)
+                }
+            })
+            .cloned()
+            .collect();
+        let source = self.build_source(cell_stmts, CellMode::Module, extra_imports);
+        self.declarations = saved;
+        source
+    }
+
     /// Synthesise a complete runnable V source.
     ///
     /// `cell_stmts` are the statements from the current cell only — they are
     /// NOT stored on `self` and will not appear in future cells.
-    fn build_source(&self, cell_stmts: &[String]) -> String {
+    ///
+    /// `extra_imports` are added for this build only, unless the session
+    /// already imports them.
+    ///
+    /// The output depends only on the accumulated declarations and the
+    /// arguments, in their order, so the same session always produces
+    /// byte-identical source.
+    fn build_source(&self, cell_stmts: &[String], mode: CellMode, extra_imports: &[&str]) -> String {
         let mut out = String::new();
 
-        let imports: Vec<&str> = self
+        // Each import once, where it was first declared: re-running a cell
+        // that imports `os` must not add a second `import os`.
+        let mut imports: Vec<&str> = Vec::new();
+        let declared = self
             .declarations
             .iter()
-            .filter(|d| d.trim_start().starts_with("import "))
-            .map(|s| s.as_str())
-            .collect();
+            .map(|d| d.trim())
+            .filter(|d| d.starts_with("import "));
+        for imp in declared.chain(extra_imports.iter().copied()) {
+            if !imports.contains(&imp) {
+                imports.push(imp);
+            }
+        }
 
         let non_imports: Vec<&str> = self
             .declarations
@@ -311,7 +1156,9 @@ impl KernelState {
             .map(|s| s.as_str())
             .collect();
 
-        out.push_str("module main\n\n");
+        if mode != CellMode::Script {
+            out.push_str("module main\n\n");
+        }
 
         for imp in &imports {
             out.push_str(imp);
@@ -326,7 +1173,14 @@ impl KernelState {
             out.push_str("\n\n");
         }
 
-        if !cell_stmts.is_empty() {
+        if mode != CellMode::Module {
+            // Scripts and unwrapped cells run top-level statements directly;
+            // V requires them to follow every definition, which they do here.
+            for stmt in cell_stmts {
+                out.push_str(stmt);
+                out.push('\n');
+            }
+        } else if !cell_stmts.is_empty() {
             out.push_str("fn main() {\n");
             for stmt in cell_stmts {
                 for line in stmt.lines() {
@@ -344,7 +1198,11 @@ impl KernelState {
 
 impl Drop for KernelState {
     fn drop(&mut self) {
-        fs::remove_dir_all(&self.tmp_dir).ok();
+        if self.config.keep_tmp {
+            log!("Keeping {} (V_KERNEL_KEEP_TMP)", self.tmp_dir.display());
+        } else {
+            fs::remove_dir_all(&self.tmp_dir).ok();
+        }
     }
 }
 
@@ -393,7 +1251,7 @@ fn parse_dump_line(line: &str) -> Option<DumpEntry> {
     }
 
     // Shorten path to basename:line for display
-    let location = if let Some(slash) = location_raw.rfind(|c| c == '/' || c == '\\') {
+    let location = if let Some(slash) = location_raw.rfind(['/', '\\']) {
         location_raw[slash + 1..].to_string()
     } else {
         location_raw.to_string()
@@ -410,11 +1268,7 @@ fn parse_dump_line(line: &str) -> Option<DumpEntry> {
         let (typ, value) = if let Some(paren) = type_value.find('(') {
             let t = type_value[..paren].trim().to_string();
             let inner = &type_value[paren + 1..];
-            let v = if inner.ends_with(')') {
-                inner[..inner.len() - 1].to_string()
-            } else {
-                inner.to_string()
-            };
+            let v = inner.strip_suffix(')').unwrap_or(inner).to_string();
             (t, v)
         } else {
             (String::new(), type_value.to_string())
@@ -517,56 +1371,292 @@ fn split_dump_output(stdout: &str) -> (String, Vec<DumpEntry>) {
     (plain, dump_entries)
 }
 
-// ── V code classifier ─────────────────────────────────────────────────────────
+// ── Rich display ──────────────────────────────────────────────────────────────
 
-fn classify(code: &str) -> (Vec<String>, Vec<String>) {
-    let mut decls = Vec::new();
-    let mut stmts = Vec::new();
+/// Source of the `vkernel` helper module.  It is written to
+/// `<tmp_dir>/vkernel/vkernel.v` when the session starts; because cells are
+/// compiled from `<tmp_dir>`, V resolves `import vkernel` to it.
+///
+/// Each helper prints a single display line
+///   %vkernel-display <mime> <payload>
+/// which the kernel strips from stdout and publishes as `display_data`.
+/// The payload is either `base64:<data>` (inline content) or a file path.
+const VKERNEL_MODULE: &str = r#"module vkernel
+
+import encoding.base64
+import json
+import os
+
+// display_html renders `s` as HTML in the notebook output.
+pub fn display_html(s string) {
+	emit('text/html', 'base64:' + base64.encode_str(s))
+}
 
-    let lines: Vec<&str> = code.lines().collect();
-    let mut i = 0;
+// display_png shows the PNG image stored at `path`.
+pub fn display_png(path string) {
+	emit('image/png', os.real_path(path))
+}
 
-    while i < lines.len() {
-        let trimmed = lines[i].trim();
+// display_latex renders `s` as LaTeX math, e.g. '$\int x\,dx$'.
+pub fn display_latex(s string) {
+	emit('text/latex', 'base64:' + base64.encode_str(s))
+}
 
-        if trimmed.is_empty() || trimmed.starts_with("//") || trimmed.starts_with("/*") {
-            i += 1;
-            continue;
-        }
+// result_json renders `v` as a JSON tree in the notebook output.
+pub fn result_json[T](v T) {
+	emit('application/json', 'base64:' + base64.encode_str(json.encode(v)))
+}
 
-        if trimmed.starts_with("#!") {
-            i += 1;
-            continue;
-        }
+// display shows `data` under any MIME type, e.g. 'text/csv' or
+// 'application/vnd.plotly.v1+json'.
+pub fn display(mime string, data string) {
+	emit(mime, 'base64:' + base64.encode_str(data))
+}
 
-        if trimmed.starts_with("module ") {
-            i += 1;
-            continue;
-        }
+// display_file shows the file at `path` under the given MIME type.
+pub fn display_file(mime string, path string) {
+	emit(mime, os.real_path(path))
+}
 
-        let is_decl = is_top_level_decl(trimmed);
+// echo shows `v` as the cell's result, prefixed with `label = ` when a
+// label is given.  The kernel injects calls to it for assignment echo.
+pub fn echo[T](label string, v T) {
+	s := if label == '' { '${v}' } else { '${label} = ${v}' }
+	println('%vkernel-result ' + base64.encode_str(s))
+	flush_stdout()
+}
 
-        if is_decl {
-            let (block, consumed) = collect_block(&lines, i);
-            decls.push(block);
-            i += consumed;
-        } else {
-            let (block, consumed) = collect_statement(&lines, i);
-            stmts.push(block);
-            i += consumed;
-        }
+fn emit(mime string, payload string) {
+	println('%vkernel-display ${mime} ${payload}')
+	flush_stdout()
+}
+"#;
+
+/// Prefix of the display lines printed by the `vkernel` helpers.
+const DISPLAY_MAGIC: &str = "%vkernel-display ";
+
+/// Prefix of the result lines printed by `vkernel.echo`; the payload is the
+/// base64-encoded `text/plain` value.
+const RESULT_MAGIC: &str = "%vkernel-result ";
+
+/// Whether `line` is one of the `vkernel` helper lines the kernel parses out
+/// of stdout once the program has finished.
+fn is_helper_line(line: &str) -> bool {
+    [DISPLAY_MAGIC, RESULT_MAGIC, TIMEIT_MAGIC]
+        .iter()
+        .any(|magic| line.starts_with(magic))
+}
+
+/// One `display_data` bundle requested by the program.
+struct DisplayItem {
+    mime: String,
+    /// Value stored under `mime` in the bundle (string or JSON value).
+    data: Value,
+}
+
+/// Parse a `%vkernel-display <mime> <payload>` line.
+///
+/// The payload is `base64:<data>`, `inline:<text>` or a file path.  Any
+/// well-formed MIME type is accepted.
+///
+/// Returns `None` for lines that are not display magics.  A malformed magic
+/// (invalid MIME, unreadable file, bad base64) is reported as `Err` so the
+/// caller can surface it instead of dropping the output silently.
+fn parse_display_line(line: &str) -> Option<Result<DisplayItem, String>> {
+    let rest = line.trim_end().strip_prefix(DISPLAY_MAGIC)?;
+    let (mime, payload) = rest.split_once(' ').unwrap_or((rest, ""));
+    Some(load_display_payload(mime, payload.trim()))
+}
+
+fn load_display_payload(mime: &str, payload: &str) -> Result<DisplayItem, String> {
+    use base64::Engine;
+    let b64 = base64::engine::general_purpose::STANDARD;
+
+    if !is_valid_mime(mime) {
+        return Err(format!("invalid display MIME type: {mime:?}"));
+    }
+
+    let bytes = if let Some(data) = payload.strip_prefix("base64:") {
+        b64.decode(data)
+            .map_err(|e| format!("invalid base64 payload for {mime}: {e}"))?
+    } else if let Some(text) = payload.strip_prefix("inline:") {
+        text.as_bytes().to_vec()
+    } else {
+        // The program may have died before (or while) writing the file it
+        // announced; say so rather than publishing an empty bundle.
+        let bytes = fs::read(payload).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => format!("display target not found: {payload}"),
+            _ => format!("cannot read display target {payload}: {e}"),
+        })?;
+        if bytes.is_empty() {
+            return Err(format!("display target is empty: {payload}"));
+        }
+        bytes
+    };
+
+    let data = if mime == "application/json" || mime.ends_with("+json") {
+        // JSON types are embedded as JSON, e.g. application/vnd.plotly.v1+json.
+        serde_json::from_slice(&bytes).map_err(|e| format!("invalid JSON for {mime}: {e}"))?
+    } else if mime.starts_with("text/") || mime.ends_with("+xml") || mime == "application/javascript" {
+        Value::String(String::from_utf8_lossy(&bytes).to_string())
+    } else {
+        // Binary data (images, PDFs, …) is base64-encoded in the Jupyter bundle.
+        Value::String(b64.encode(&bytes))
+    };
+
+    Ok(DisplayItem {
+        mime: mime.to_string(),
+        data,
+    })
+}
+
+/// Whether `mime` looks like `type/subtype` (RFC 6838 restricted names).
+fn is_valid_mime(mime: &str) -> bool {
+    let valid_name = |s: &str| {
+        !s.is_empty()
+            && s.len() <= 127
+            && s.starts_with(|c: char| c.is_ascii_alphanumeric())
+            && s.chars().all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c))
+    };
+    matches!(mime.split_once('/'), Some((t, sub)) if valid_name(t) && valid_name(sub))
+}
+
+/// Program stdout with the `vkernel` helper lines pulled out.
+struct DisplayOutput {
+    /// Everything that was not a helper line.
+    plain: String,
+    items: Vec<DisplayItem>,
+    /// `text/plain` values from `vkernel.echo`, in order.
+    results: Vec<String>,
+    /// Malformed helper lines, to report instead of dropping.
+    errors: Vec<String>,
+}
+
+/// Split stdout into plain text, display items and echoed results.
+fn split_display_output(stdout: &str) -> DisplayOutput {
+    use base64::Engine;
+    let b64 = base64::engine::general_purpose::STANDARD;
+
+    let mut plain_lines: Vec<&str> = Vec::new();
+    let mut items = Vec::new();
+    let mut results = Vec::new();
+    let mut errors = Vec::new();
+
+    for line in stdout.lines() {
+        if let Some(payload) = line.trim_end().strip_prefix(RESULT_MAGIC) {
+            match b64.decode(payload) {
+                Ok(bytes) => results.push(String::from_utf8_lossy(&bytes).into_owned()),
+                Err(e) => errors.push(format!("invalid base64 result: {e}")),
+            }
+            continue;
+        }
+        match parse_display_line(line) {
+            Some(Ok(item)) => items.push(item),
+            Some(Err(e)) => errors.push(e),
+            None => plain_lines.push(line),
+        }
+    }
+
+    let mut plain = plain_lines.join("\n");
+    if !plain.is_empty() {
+        plain.push('\n');
+    }
+
+    DisplayOutput {
+        plain,
+        items,
+        results,
+        errors,
+    }
+}
+
+// ── V code classifier ─────────────────────────────────────────────────────────
+
+/// Render what `classify` makes of `code`, for `%classify`.
+fn format_classification(code: &str) -> String {
+    let (decls, stmts) = classify(code);
+    let mut out = format!(
+        "[v-kernel] {} declaration(s), {} statement(s):\n",
+        decls.len(),
+        stmts.len()
+    );
+    for (kind, blocks) in [("declaration", &decls), ("statement", &stmts)] {
+        for (i, block) in blocks.iter().enumerate() {
+            out.push_str(&format!("\n{kind} {}:\n", i + 1));
+            for line in block.lines() {
+                out.push_str(&format!("    {line}\n"));
+            }
+        }
+    }
+    out
+}
+
+fn classify(code: &str) -> (Vec<String>, Vec<String>) {
+    let mut decls = Vec::new();
+    let mut stmts = Vec::new();
+
+    let mut lines: Vec<&str> = code.lines().collect();
+    let mut i = 0;
+
+    while i < lines.len() {
+        let trimmed = lines[i].trim();
+
+        if trimmed.is_empty() || trimmed.starts_with("//") || trimmed.starts_with("/*") {
+            i += 1;
+            continue;
+        }
+
+        if trimmed.starts_with("#!") {
+            i += 1;
+            continue;
+        }
+
+        if trimmed.starts_with("module ") {
+            i += 1;
+            continue;
+        }
+
+        let is_decl = is_top_level_decl(trimmed);
+
+        if is_decl {
+            let (mut block, mut consumed) = collect_block(&lines, i);
+            // Keep attribute lines attached to the declaration they annotate.
+            while block.lines().last().is_some_and(is_attribute_line) {
+                let next = (i + consumed..lines.len()).find(|&j| !lines[j].trim().is_empty());
+                match next {
+                    Some(j) if is_top_level_decl(lines[j].trim()) => {
+                        let (more, n) = collect_block(&lines, j);
+                        block.push('\n');
+                        block.push_str(&more);
+                        consumed = j + n - i;
+                    }
+                    _ => break,
+                }
+            }
+            decls.push(block);
+            i += consumed;
+        } else {
+            let (block, consumed, rest) = collect_statement(&lines, i);
+            stmts.push(block);
+            match rest {
+                // Re-classify the trailing code in place of the closing line.
+                Some(rest) => {
+                    i += consumed - 1;
+                    lines[i] = rest;
+                }
+                None => i += consumed,
+            }
+        }
     }
 
     (decls, stmts)
 }
 
 fn is_top_level_decl(line: &str) -> bool {
-    let stripped = line
-        .trim_start_matches("pub ")
-        .trim_start_matches("mut ")
-        .trim_start_matches("static ");
+    let stripped = strip_modifiers(strip_attributes(line));
 
-    if stripped.starts_with('[') || stripped.starts_with("@[") {
+    // An attribute on its own line belongs to the declaration below it.
+    if stripped.is_empty() {
         return true;
     }
 
@@ -584,41 +1674,210 @@ fn is_top_level_decl(line: &str) -> bool {
     keywords.iter().any(|kw| stripped.starts_with(kw))
 }
 
-fn collect_block(lines: &[&str], start: usize) -> (String, usize) {
-    let first = lines[start];
+/// Strip leading `pub` / `mut` / `static` modifiers, in any order.
+fn strip_modifiers(line: &str) -> &str {
+    let mut rest = line.trim_start();
+    loop {
+        let next = ["pub ", "mut ", "static "]
+            .iter()
+            .find_map(|m| rest.strip_prefix(m));
+        match next {
+            Some(r) => rest = r.trim_start(),
+            None => return rest,
+        }
+    }
+}
 
-    if !first.contains('{') {
-        return (first.to_string(), 1);
+/// Strip leading attributes (`@[inline]`, legacy `[heap]`), including any
+/// modifiers between them.  Returns the empty string for an
+/// attribute-only line.
+fn strip_attributes(line: &str) -> &str {
+    let mut rest = line.trim_start();
+    loop {
+        let open = if rest.starts_with("@[") {
+            2
+        } else if rest.starts_with('[') {
+            1
+        } else {
+            return rest;
+        };
+        let Some(close) = rest[open..].find(']') else {
+            return rest;
+        };
+        let after = rest[open + close + 1..].trim_start();
+        if !after.is_empty() && !after.starts_with(['@', '[']) && !is_top_level_decl(after) {
+            // `[1, 2].len()` and friends — not an attribute.
+            return rest;
+        }
+        rest = strip_modifiers(after);
     }
+}
 
-    let mut depth = 0i32;
-    let mut collected = Vec::new();
+/// Whether `line` consists only of attributes.
+fn is_attribute_line(line: &str) -> bool {
+    let line = line.trim();
+    (line.starts_with('[') || line.starts_with("@[")) && strip_attributes(line).is_empty()
+}
+
+/// The operand of a cell-level `return <expr>` statement.  A bare `return`
+/// (a valid early exit from `main`) yields `None`.
+fn top_level_return(stmt: &str) -> Option<&str> {
+    let expr = stmt.trim().strip_prefix("return")?;
+    if !expr.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let expr = expr.trim();
+    (!expr.is_empty()).then_some(expr)
+}
+
+/// Variables assigned by `stmt` when it is a plain `:=` / `=` assignment
+/// (`x := f()`, `a, b = b, a`), for assignment echo.  Anything else —
+/// compound assignments, comparisons, multi-line statements — yields none.
+fn assignment_targets(stmt: &str) -> Vec<String> {
+    if stmt.contains('\n') {
+        return Vec::new();
+    }
+    let bytes = stmt.as_bytes();
+    let op = stmt.find(":=").or_else(|| {
+        stmt.char_indices().map(|(i, _)| i).find(|&i| {
+            bytes[i] == b'='
+                && bytes.get(i + 1) != Some(&b'=')
+                && (i == 0 || !b"=!<>+-*/%&|^:".contains(&bytes[i - 1]))
+        })
+    });
+    let Some(op) = op else {
+        return Vec::new();
+    };
+
+    let mut targets = Vec::new();
+    for target in stmt[..op].split(',') {
+        let target = target.trim();
+        let target = target.strip_prefix("mut ").unwrap_or(target).trim();
+        let is_path = !target.is_empty()
+            && target
+                .split('.')
+                .all(|part| part.starts_with(|c: char| c.is_alphabetic() || c == '_')
+                    && part.chars().all(|c| c.is_alphanumeric() || c == '_'));
+        if !is_path {
+            return Vec::new();
+        }
+        if target != "_" {
+            targets.push(target.to_string());
+        }
+    }
+    targets
+}
+
+/// Modules V reported as imported but never used, from build diagnostics
+/// (`line 2:8: error: module 'os' is imported but never used`).
+fn unused_imports(diagnostics: &str) -> Vec<&str> {
+    diagnostics
+        .lines()
+        .filter_map(|line| {
+            let rest = line.split_once("error: module '")?.1;
+            Some(rest.split_once("' is imported but never used")?.0)
+        })
+        .collect()
+}
+
+/// The name a declaration introduces (`fn area`, `struct Point`, …), used by
+/// `%edit`.  Methods are named by the method alone.
+fn decl_name(decl: &str) -> Option<&str> {
+    let line = decl.trim_start();
+    let line = line.strip_prefix("pub ").unwrap_or(line);
+    let (kw, rest) = line.split_once(char::is_whitespace)?;
+    if !matches!(kw, "fn" | "struct" | "enum" | "interface" | "union" | "type" | "const") {
+        return None;
+    }
+    let mut rest = rest.trim_start();
+    if kw == "fn" && rest.starts_with('(') {
+        rest = rest[rest.find(')')? + 1..].trim_start();
+    }
+    let end = rest
+        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(rest.len());
+    (end > 0).then(|| &rest[..end])
+}
+
+/// What identifies a declaration for `%import_session`: its first line up to
+/// the end of its name (`pub struct Point`, `fn (p Point) area`), so a
+/// method does not clash with a function of the same name.
+fn decl_key(decl: &str) -> Option<&str> {
+    let line = decl.trim_start();
+    let name = decl_name(line)?;
+    // `name` is a slice of `line`, on its first line.
+    let end = name.as_ptr() as usize - line.as_ptr() as usize + name.len();
+    Some(&line[..end])
+}
+
+/// The file name, without extension, a cell's `source` is written under:
+/// `pattern` (`V_KERNEL_SOURCE_NAME`) with `{n}` replaced by the execution
+/// count and `{hash}` by the start of the source's SHA-256.
+fn source_file_name(pattern: &str, execution_count: u32, source: &str) -> String {
+    let mut name = pattern.replace("{n}", &execution_count.to_string());
+    if name.contains("{hash}") {
+        let digest = hex::encode(<Sha256 as sha2::Digest>::digest(source.as_bytes()));
+        name = name.replace("{hash}", &digest[..16]);
+    }
+    name
+}
+
+/// Shown when a cell brings its own `fn main`, which the kernel merges into
+/// the one it generates.
+const MAIN_MERGED_NOTE: &str = "[v-kernel] This cell defines `fn main`, but the kernel writes `fn main()` \
+itself: the body of yours runs as the cell's statements, followed by any statements outside it, \
+and it is not kept for later cells.\n\
+[v-kernel] To compile the cell exactly as written, start it with %nowrap.\n";
+
+/// The body of `decl` if it is `fn main()`.
+fn main_body(decl: &str) -> Option<&str> {
+    let rest = decl.trim().strip_prefix("fn main")?.trim_start();
+    let rest = rest.strip_prefix('(')?.trim_start().strip_prefix(')')?.trim_start();
+    rest.strip_prefix('{')?.strip_suffix('}')
+}
+
+/// Collect the declaration starting at `lines[start]`: up to the `}` that
+/// closes its body, or, without a body, up to the end of its signature.
+/// Parentheses and brackets are tracked too, so a parameter (or generic
+/// parameter) list continued on the next line stays part of it.
+fn collect_block(lines: &[&str], start: usize) -> (String, usize) {
+    let mut braces = 0i32;
+    let mut brackets = 0i32;
+    let mut has_body = false;
     let mut i = start;
 
     while i < lines.len() {
-        let line = lines[i];
-        for ch in line.chars() {
+        for ch in lines[i].chars() {
             match ch {
-                '{' => depth += 1,
-                '}' => depth -= 1,
+                '{' => {
+                    braces += 1;
+                    has_body = true;
+                }
+                '}' => braces -= 1,
+                '(' | '[' => brackets += 1,
+                ')' | ']' => brackets -= 1,
                 _ => {}
             }
         }
-        collected.push(line);
         i += 1;
-        if depth <= 0 {
+        if braces <= 0 && (has_body || brackets <= 0) {
             break;
         }
     }
 
-    (collected.join("\n"), i - start)
+    (lines[start..i].join("\n"), i - start)
 }
 
-fn collect_statement(lines: &[&str], start: usize) -> (String, usize) {
+/// Collect a statement starting at `lines[start]`, following braces across
+/// lines.  Returns the statement, the number of lines consumed, and any code
+/// after the closing brace on the last line (`} x := 1`) that starts a new
+/// statement and must be classified separately.  Continuations such as
+/// `} else {`, `} or { 0 }` and `})` stay part of the statement.
+fn collect_statement<'a>(lines: &[&'a str], start: usize) -> (String, usize, Option<&'a str>) {
     let first = lines[start];
 
     if !first.contains('{') {
-        return (first.to_string(), 1);
+        return (first.to_string(), 1, None);
     }
 
     let mut depth = 0i32;
@@ -627,170 +1886,1930 @@ fn collect_statement(lines: &[&str], start: usize) -> (String, usize) {
 
     while i < lines.len() {
         let line = lines[i];
-        for ch in line.chars() {
+        let mut closed_at = None;
+        for (pos, ch) in line.char_indices() {
             match ch {
                 '{' => depth += 1,
-                '}' => depth -= 1,
+                '}' => {
+                    depth -= 1;
+                    if depth <= 0 {
+                        closed_at = Some(pos + 1);
+                    }
+                }
                 _ => {}
             }
         }
-        collected.push(line);
         i += 1;
         if depth <= 0 {
+            if let Some(end) = closed_at.filter(|&end| starts_new_statement(&line[end..])) {
+                collected.push(&line[..end]);
+                return (collected.join("\n"), i - start, Some(line[end..].trim_start()));
+            }
+            collected.push(line);
             break;
         }
+        collected.push(line);
     }
 
-    (collected.join("\n"), i - start)
+    (collected.join("\n"), i - start, None)
 }
 
-// ── V runner ─────────────────────────────────────────────────────────────────
+/// Whether the text after a closing brace begins a new statement rather than
+/// continuing the current one (`else`, `or`, `.method()`, `)`, `,`, …).
+fn starts_new_statement(rest: &str) -> bool {
+    let rest = rest.trim_start();
+    if rest.starts_with("//") || rest.starts_with("/*") {
+        return false;
+    }
+    let word: String = rest
+        .chars()
+        .take_while(|c| c.is_alphanumeric() || *c == '_')
+        .collect();
+    !word.is_empty() && !matches!(word.as_str(), "else" | "or" | "as" | "is" | "in")
+}
 
-fn run_v(src: &PathBuf, state: &mut KernelState) -> (String, String, bool) {
-    let mut cmd = Command::new("v");
-    cmd.arg("run")
-        .arg(src)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped());
+/// An open construct while scanning for `check_brackets`.
+enum Frame {
+    /// `(`, `[` or `{`, with the line and column it opened at.
+    Bracket(char, usize, usize),
+    /// A string literal: its quote, whether it is raw (`r'…'`), and where it
+    /// opened.
+    Str(char, bool, usize, usize),
+    /// `${…}` inside a string.
+    Interp,
+}
 
-    let child = match cmd.spawn() {
-        Ok(c) => c,
-        Err(e) => {
-            return (
-                String::new(),
-                format!(
-                    "Could not start `v`. Is V installed and in PATH?\nError: {e}"
-                ),
-                true,
-            );
+/// Check that brackets, strings and block comments in `source` are balanced,
+/// so a cell with an obvious typo is rejected without running `v`.
+///
+/// Errors use V's `line L:C: error: …` format.  Anything unusual that this
+/// scanner does not understand is left for the compiler to judge: the check
+/// only fails for input V would reject anyway.
+fn check_brackets(source: &str) -> Result<(), String> {
+    let mut stack: Vec<Frame> = Vec::new();
+    let mut chars = source.chars().peekable();
+    let (mut line, mut col) = (1, 0);
+    let mut prev = '\n';
+    let mut prev2 = '\n';
+
+    while let Some(ch) = chars.next() {
+        if ch == '\n' {
+            line += 1;
+            col = 0;
+        } else {
+            col += 1;
         }
-    };
+        let (before, before2) = (prev, prev2);
+        prev2 = prev;
+        prev = ch;
+
+        if let Some(Frame::Str(quote, raw, _, _)) = stack.last() {
+            let (quote, raw) = (*quote, *raw);
+            if ch == quote {
+                stack.pop();
+            } else if ch == '\\' && !raw {
+                if chars.next() == Some('\n') {
+                    line += 1;
+                    col = 0;
+                } else {
+                    col += 1;
+                }
+                prev = '\0';
+            } else if ch == '$' && !raw && chars.peek() == Some(&'{') {
+                chars.next();
+                col += 1;
+                stack.push(Frame::Interp);
+            }
+            continue;
+        }
+
+        match ch {
+            '/' if chars.peek() == Some(&'/') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        line += 1;
+                        col = 0;
+                        break;
+                    }
+                }
+                prev = '\n';
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let (open_line, open_col) = (line, col);
+                let mut depth = 1;
+                let mut last = '\0';
+                while depth > 0 {
+                    let Some(c) = chars.next() else {
+                        return Err(format!("line {open_line}:{open_col}: error: unterminated comment"));
+                    };
+                    if c == '\n' {
+                        line += 1;
+                        col = 0;
+                    } else {
+                        col += 1;
+                    }
+                    match (last, c) {
+                        ('/', '*') => {
+                            depth += 1;
+                            last = '\0';
+                        }
+                        ('*', '/') => {
+                            depth -= 1;
+                            last = '\0';
+                        }
+                        _ => last = c,
+                    }
+                }
+                prev = ' ';
+            }
+            '\'' | '"' => {
+                let raw = before == 'r' && !(before2.is_alphanumeric() || before2 == '_');
+                stack.push(Frame::Str(ch, raw, line, col));
+            }
+            '`' => {
+                // Rune literal.
+                let mut escaped = false;
+                for c in chars.by_ref() {
+                    col += 1;
+                    match c {
+                        '\\' if !escaped => escaped = true,
+                        '`' if !escaped => break,
+                        _ => escaped = false,
+                    }
+                }
+            }
+            '(' | '[' | '{' => stack.push(Frame::Bracket(ch, line, col)),
+            ')' | ']' | '}' => {
+                let open = match ch {
+                    ')' => '(',
+                    ']' => '[',
+                    _ => '{',
+                };
+                match stack.last() {
+                    Some(Frame::Interp) if ch == '}' => {
+                        stack.pop();
+                    }
+                    Some(Frame::Bracket(c, _, _)) if *c == open => {
+                        stack.pop();
+                    }
+                    Some(Frame::Bracket(c, l, cl)) => {
+                        return Err(format!(
+                            "line {line}:{col}: error: unexpected `{ch}`, `{c}` opened at line {l}:{cl} is not closed"
+                        ));
+                    }
+                    _ => return Err(format!("line {line}:{col}: error: unexpected `{ch}`")),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    match stack.pop() {
+        None => Ok(()),
+        Some(Frame::Bracket(c, l, cl)) => Err(format!("line {l}:{cl}: error: unclosed `{c}`")),
+        Some(Frame::Str(_, _, l, cl)) => Err(format!("line {l}:{cl}: error: unterminated string literal")),
+        Some(Frame::Interp) => Err(format!("line {line}:{col}: error: unterminated string interpolation")),
+    }
+}
+
+/// Run `check_brackets` on the source at `src`, returning the error to
+/// report instead of compiling, if any.  An unreadable file is left for the
+/// compiler to report.
+fn precheck_source(src: &Path) -> Option<ExecOutput> {
+    let source = fs::read_to_string(src).ok()?;
+    check_brackets(&source)
+        .err()
+        .map(|e| ExecOutput::failed(ErrorKind::CompileError, format!("{e}\n")))
+}
 
-    state.running_pid = Some(child.id());
+// ── V runner ─────────────────────────────────────────────────────────────────
 
-    let output = match child.wait_with_output() {
+/// Compile `src` to a binary, then run it.
+///
+/// `v run` would be one step, but it interleaves the compiler's own messages
+/// with the program's output.  Building first keeps "the compiler said" in
+/// `diagnostics` and "the program said" in `stdout`/`stderr`.
+fn run_v(src: &Path, state: &mut KernelState) -> ExecOutput {
+    // Save the compile round-trip for input that can never build.
+    if let Some(out) = precheck_source(src) {
+        return out;
+    }
+    let bin = src.with_extension(env::consts::EXE_EXTENSION);
+
+    // ── Compile ───────────────────────────────────────────────────────────────
+    let v = state.v_binary.clone();
+    let mut cmd = state.v_command();
+    cmd.arg("-o").arg(&bin).arg(src);
+    let compile_started = Instant::now();
+    let build = match run_child(cmd, state, false) {
         Ok(o) => o,
-        Err(e) => {
-            state.running_pid = None;
-            return (String::new(), format!("Failed to wait on `v run`: {e}"), true);
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return ExecOutput::error(format!(
+                "Could not start `{}`. Is V installed and in PATH?\nError: {e}",
+                v.display()
+            ));
         }
+        Err(e) => return ExecOutput::error(format!("Failed to run `{}`: {e}", v.display())),
     };
 
-    state.running_pid = None;
-
-    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-    let raw_stderr = String::from_utf8_lossy(&output.stderr).to_string();
-    // Base is_error purely on exit status. Do NOT check stdout.is_empty() —
-    // dump() writes to stderr on success, so stderr is non-empty on normal runs.
-    let is_error = !output.status.success() && !raw_stderr.contains("Killed");
-
+    // The compiler prints to both streams depending on version and message
+    // kind; treat everything it said as diagnostics.
+    let encoding = state.config.output_encoding;
+    let mut compiler = decode_output(&build.stdout, encoding);
+    compiler.push_str(&decode_output(&build.stderr, encoding));
     // Rewrite cell_N.v:LINE:COL: references in error messages so they point to
     // the line number within the cell rather than a meaningless temp filename.
     // e.g. "/tmp/v-kernel-abc/cell_3.v:7:5: error: ..." → "line 7:5: error: ..."
-    let stderr = rewrite_cell_paths(&raw_stderr, src);
+    let compiler = rewrite_cell_paths(&compiler, src);
 
-    (stdout, stderr, is_error)
-}
+    let compile_time = Some(compile_started.elapsed());
+    if !build.status.success() {
+        return ExecOutput {
+            compile_time,
+            ..ExecOutput::failed(build.failure_kind(ErrorKind::CompileError), compiler)
+        };
+    }
 
-/// Replace occurrences of the temp cell filename in `text` with `line N`.
-///
-/// The V compiler emits paths in one of two forms:
-///   /full/path/to/cell_3.v:7:5: error: …      (absolute path)
-///   cell_3.v:7:5: error: …                    (basename only)
-///
-/// Both are replaced with `line 7:5: error: …` so error messages make
-/// sense in the context of the cell the user just executed.
-fn rewrite_cell_paths(text: &str, src: &PathBuf) -> String {
-    // Build the two patterns to replace: full path and basename.
-    let full = src.to_string_lossy().to_string();
-    let basename = src
-        .file_name()
-        .map(|n| n.to_string_lossy().to_string())
-        .unwrap_or_default();
+    // With streaming, warnings must go out before the program's output does.
+    let diagnostics_sent = !compiler.is_empty()
+        && state.send_live("stream", json!({ "name": "stderr", "text": compiler }));
 
-    // Replace full path first (it subsumes the basename on most systems),
-    // then any remaining basename-only occurrences.
-    let step1 = if !full.is_empty() {
-        text.replace(&full, "cell")
-    } else {
-        text.to_string()
-    };
-    let step2 = if !basename.is_empty() && basename != full {
-        step1.replace(&basename, "cell")
-    } else {
-        step1
+    // ── Run ───────────────────────────────────────────────────────────────────
+    let run_started = Instant::now();
+    let mut program = Command::new(&bin);
+    program.envs(&state.program_env);
+    let output = match run_child(program, state, true) {
+        Ok(o) => o,
+        Err(e) => {
+            return ExecOutput {
+                diagnostics: compiler,
+                diagnostics_sent,
+                compile_time,
+                ..ExecOutput::error(format!("Failed to run compiled cell: {e}"))
+            };
+        }
     };
-
-    // Now rewrite "cell:LINE:COL:" → "line LINE:COL:" and "cell:LINE:" → "line LINE:"
-    // The V compiler always separates the location with `:` so a simple
-    // prefix replacement on `cell:` is sufficient.
-    step2.replace("cell:", "line ")
+    fs::remove_file(&bin).ok();
+
+    let stdout = decode_output(&output.stdout, encoding);
+    let mut raw_stderr = decode_output(&output.stderr, encoding);
+    if let Some(mb) = state.mem_limit_mb {
+        if !output.status.success() && is_out_of_memory(&raw_stderr) {
+            raw_stderr.push_str(&format!(
+                "[v-kernel] Memory limit exceeded: the program could not allocate within \
+                 {mb} MB (set with %memlimit or V_KERNEL_MEM_LIMIT_MB).\n"
+            ));
+        }
+    }
+    // Base the error purely on exit status. Do NOT check stdout.is_empty() —
+    // dump() writes to stderr on success, so stderr is non-empty on normal runs.
+    let error = (!output.status.success() && !raw_stderr.contains("Killed")).then(|| {
+        if state.mem_limit_mb.is_some() && is_out_of_memory(&raw_stderr) {
+            ErrorKind::MemoryLimit
+        } else if raw_stderr.contains("V panic:") {
+            output.failure_kind(ErrorKind::VPanic)
+        } else {
+            output.failure_kind(ErrorKind::RuntimeError)
+        }
+    });
+
+    ExecOutput {
+        stdout,
+        stderr: rewrite_cell_paths(&raw_stderr, src),
+        diagnostics: compiler,
+        diagnostics_sent,
+        error,
+        exit_code: output.status.code(),
+        compile_time,
+        run_time: Some(run_started.elapsed()),
+        ..Default::default()
+    }
 }
 
-// ── Process interrupt ───────────────────────────────────────────────────────
-
-fn interrupt_process(pid: u32) {
-    #[cfg(unix)]
-    {
-        unsafe {
-            libc::kill(pid as libc::pid_t, libc::SIGINT);
-        }
+/// Prefix of the line a `%%timeit` program prints with its measurements:
+/// the loop count followed by V's rendering of the per-loop times in ns.
+const TIMEIT_MAGIC: &str = "%vkernel-timeit ";
+
+/// Parse `%%timeit` arguments into (loops per round, rounds).
+fn parse_timeit_args(args: &str) -> Result<(u32, u32), String> {
+    let (mut loops, mut rounds) = (1000, 7);
+    let mut words = args.split_whitespace();
+    while let Some(flag) = words.next() {
+        let target = match flag {
+            "-n" => &mut loops,
+            "-r" => &mut rounds,
+            _ => return Err(format!("Unknown %%timeit option `{flag}`.")),
+        };
+        *target = words
+            .next()
+            .and_then(|v| v.parse().ok())
+            .filter(|&v| v > 0)
+            .ok_or_else(|| format!("{flag} needs a positive whole number."))?;
     }
-    #[cfg(windows)]
-    {
-        use windows_sys::Win32::Foundation::CloseHandle;
+    Ok((loops, rounds))
+}
+
+/// Replace the `%%timeit` measurement line in `stdout` with a summary like
+/// IPython's: `1.23 µs ± 45.6 ns per loop (mean ± std. dev. of 7 runs,
+/// 1000 loops each)`.
+fn format_timeit_output(stdout: &str) -> String {
+    let mut out = String::new();
+    for line in stdout.lines() {
+        let Some(rest) = line.strip_prefix(TIMEIT_MAGIC) else {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        };
+        let (loops, list) = rest.split_once(' ').unwrap_or((rest, ""));
+        let times: Vec<f64> = list
+            .trim_matches(|c| c == '[' || c == ']')
+            .split(',')
+            .filter_map(|t| t.trim().parse().ok())
+            .collect();
+        if times.is_empty() {
+            continue;
+        }
+        let n = times.len() as f64;
+        let mean = times.iter().sum::<f64>() / n;
+        let std = (times.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / n).sqrt();
+        let best = times.iter().cloned().fold(f64::INFINITY, f64::min);
+        out.push_str(&format!(
+            "{} ± {} per loop (mean ± std. dev. of {} runs, {loops} loops each)\nbest: {} per loop\n",
+            format_duration_ns(mean),
+            format_duration_ns(std),
+            times.len(),
+            format_duration_ns(best),
+        ));
+    }
+    out
+}
+
+/// Format nanoseconds with a unit that keeps the number readable.
+fn format_duration_ns(ns: f64) -> String {
+    let (value, unit) = if ns < 1e3 {
+        (ns, "ns")
+    } else if ns < 1e6 {
+        (ns / 1e3, "µs")
+    } else if ns < 1e9 {
+        (ns / 1e6, "ms")
+    } else {
+        (ns / 1e9, "s")
+    };
+    format!("{value:.3} {unit}")
+}
+
+/// Longest generated C shown by `%%c`, in lines.  Even tiny programs pull in
+/// thousands of lines of V runtime.
+const C_OUTPUT_MAX_LINES: usize = 2000;
+
+/// Translate `src` to C with `v -o <file>.c` and return the C as output,
+/// truncated to `C_OUTPUT_MAX_LINES`.
+fn generate_c(src: &Path, state: &mut KernelState) -> ExecOutput {
+    if let Some(out) = precheck_source(src) {
+        return out;
+    }
+    let c_path = src.with_extension("c");
+    let v = state.v_binary.clone();
+    let mut cmd = state.v_command();
+    cmd.arg("-o").arg(&c_path).arg(src);
+    let build = match run_child(cmd, state, false) {
+        Ok(o) => o,
+        Err(e) => return ExecOutput::error(format!("Failed to run `{}`: {e}", v.display())),
+    };
+
+    let encoding = state.config.output_encoding;
+    let mut compiler = decode_output(&build.stdout, encoding);
+    compiler.push_str(&decode_output(&build.stderr, encoding));
+    let compiler = rewrite_cell_paths(&compiler, src);
+    if !build.status.success() {
+        return ExecOutput::failed(build.failure_kind(ErrorKind::CompileError), compiler);
+    }
+
+    let c = match fs::read_to_string(&c_path) {
+        Ok(c) => c,
+        Err(e) => return ExecOutput::error(format!("Cannot read generated C: {e}")),
+    };
+    fs::remove_file(&c_path).ok();
+
+    let total = c.lines().count();
+    let mut stdout: String = c
+        .lines()
+        .take(C_OUTPUT_MAX_LINES)
+        .flat_map(|l| [l, "\n"])
+        .collect();
+    if total > C_OUTPUT_MAX_LINES {
+        stdout.push_str(&format!(
+            "\n[v-kernel] … {} more line(s) not shown ({total} total).\n",
+            total - C_OUTPUT_MAX_LINES
+        ));
+    }
+
+    ExecOutput {
+        stdout,
+        diagnostics: compiler,
+        ..Default::default()
+    }
+}
+
+/// Probe what the `v` at `v` supports, logging the result.
+fn probe_capabilities(v: &Path, scratch: &Path) -> VCapabilities {
+    let capabilities = VCapabilities::probe(v, scratch);
+    log!("Capabilities of {}:\n{}", v.display(), capabilities.format().trim_end());
+    capabilities
+}
+
+/// The output of `<v> version`, e.g. `V 0.4.8 1a2b3c4`.
+fn v_version(v: &Path) -> Result<String, String> {
+    let output = Command::new(v)
+        .arg("version")
+        .output()
+        .map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!("`version` exited with {}", output.status));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Number of child processes currently running under `run_child`.
+static ACTIVE_CHILDREN: AtomicUsize = AtomicUsize::new(0);
+
+/// A claim on one of the `max_processes` child slots, released on drop.
+struct ChildSlot;
+
+impl ChildSlot {
+    fn acquire(limit: usize) -> std::io::Result<Self> {
+        ACTIVE_CHILDREN
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < limit).then_some(n + 1))
+            .map(|_| ChildSlot)
+            .map_err(|n| {
+                std::io::Error::new(
+                    std::io::ErrorKind::WouldBlock,
+                    format!("{n} child process(es) already running (limit {limit})"),
+                )
+            })
+    }
+}
+
+impl Drop for ChildSlot {
+    fn drop(&mut self) {
+        ACTIVE_CHILDREN.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Decode child output.  With an explicit `encoding` (`V_KERNEL_OUTPUT_ENCODING`)
+/// that is used as-is; otherwise output is taken as UTF-8, and on Windows
+/// output that is not valid UTF-8 is decoded with the system code page.
+fn decode_output(bytes: &[u8], encoding: Option<&'static encoding_rs::Encoding>) -> String {
+    if let Some(encoding) = encoding {
+        return encoding.decode(bytes).0.into_owned();
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => match system_encoding() {
+            Some(encoding) => encoding.decode(bytes).0.into_owned(),
+            None => String::from_utf8_lossy(bytes).into_owned(),
+        },
+    }
+}
+
+/// The encoding of the console (or, without one, ANSI) code page.
+#[cfg(windows)]
+fn system_encoding() -> Option<&'static encoding_rs::Encoding> {
+    use windows_sys::Win32::{Globalization::GetACP, System::Console::GetConsoleOutputCP};
+
+    // SAFETY: both calls take no arguments and only read process state.
+    let code_page = match unsafe { GetConsoleOutputCP() } {
+        0 => unsafe { GetACP() },
+        cp => cp,
+    };
+    let label = match code_page {
+        866 => "ibm866".to_string(),
+        932 => "shift_jis".to_string(),
+        936 => "gbk".to_string(),
+        949 => "euc-kr".to_string(),
+        950 => "big5".to_string(),
+        65001 => "utf-8".to_string(),
+        cp => format!("windows-{cp}"),
+    };
+    encoding_rs::Encoding::for_label(label.as_bytes())
+}
+
+#[cfg(not(windows))]
+fn system_encoding() -> Option<&'static encoding_rs::Encoding> {
+    None
+}
+
+/// How long to keep reading a child's pipes after it has exited.  A process
+/// that daemonized out of the child's process group can hold them open
+/// forever; past this grace period its output is abandoned.
+const PIPE_DRAIN_GRACE: Duration = Duration::from_millis(500);
+
+/// Spawn `cmd` with piped output and wait for it, publishing its PID in
+/// `state.running_pid` for the duration so `interrupt_request` can reach it.
+/// `program` is set for a cell's program, as opposed to a `v` build: only
+/// its stdout is streamed and only it is held to `state.mem_limit_mb`.
+///
+/// At most `Config::max_processes` children run at once; beyond that the
+/// spawn is refused rather than queued, so a runaway loop of requests can
+/// never fork-bomb the machine.
+///
+/// A child still running after `state.timeout` is stopped (with its process
+/// group), and a note saying so is added to its stderr.
+///
+/// On Unix the child leads its own process group.  Interrupts are sent to
+/// the whole group, and whatever is left of it once the child exits
+/// (background jobs, forked workers) is killed, so a cell never leaves
+/// processes behind.  Only the child's exit is waited for: pipes are read on
+/// separate threads and given `PIPE_DRAIN_GRACE` to reach EOF, so a
+/// grandchild that escaped the group with `setsid` cannot hang the cell.
+fn run_child(mut cmd: Command, state: &KernelState, program: bool) -> std::io::Result<ChildOutput> {
+    let _slot = ChildSlot::acquire(state.config.max_processes)?;
+    let mem_limit = state.mem_limit_mb.filter(|_| program).map(|mb| mb.saturating_mul(1024 * 1024));
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+        if let Some(bytes) = mem_limit {
+            // SAFETY: setrlimit is async-signal-safe and touches only the
+            // child being set up.
+            unsafe {
+                cmd.pre_exec(move || limit_address_space(bytes));
+            }
+        }
+    }
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    *state.running_pid.lock().unwrap() = Some(child.id());
+    #[cfg(windows)]
+    let _job = mem_limit.and_then(|bytes| MemoryLimitJob::assign(&child, bytes));
+    let mut stdout = spawn_pipe_reader(child.stdout.take());
+    if let (true, Some(live), Some(window)) = (program, &state.live, state.config.stream_window) {
+        stdout = coalesce_stdout(stdout, live.clone(), window, &state.config);
+    }
+    let stderr = spawn_pipe_reader(child.stderr.take());
+    let status = match state.timeout {
+        Some(limit) => wait_with_timeout(&mut child, limit),
+        None => child.wait().map(|status| (status, false)),
+    };
+    kill_process_group(child.id());
+    *state.running_pid.lock().unwrap() = None;
+    let (status, timed_out) = status?;
+    let interrupted = !status.success() && INTERRUPTED.swap(false, Ordering::SeqCst);
+
+    let deadline = Instant::now() + PIPE_DRAIN_GRACE;
+    let (stdout, stdout_done) = drain_pipe(&stdout, deadline);
+    let (mut stderr, stderr_done) = drain_pipe(&stderr, deadline);
+    if timed_out {
+        let limit = state.timeout.unwrap_or_default().as_secs_f64();
+        stderr.extend_from_slice(
+            format!("[v-kernel] Timed out after {limit}s; the process was stopped.\n").as_bytes(),
+        );
+    }
+    if !(stdout_done && stderr_done) {
+        log!("child {} exited but a detached process still holds its output open", child.id());
+        stderr.extend_from_slice(
+            b"note: a detached background process is still holding this cell's output open; \
+              later output from it is discarded\n",
+        );
+    }
+    Ok(ChildOutput {
+        status,
+        stdout,
+        stderr,
+        timed_out,
+        interrupted,
+    })
+}
+
+/// What `run_child` collected from a finished child.
+struct ChildOutput {
+    status: std::process::ExitStatus,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    /// The child was stopped for running past the time limit.
+    timed_out: bool,
+    /// The child was stopped by an interrupt request.
+    interrupted: bool,
+}
+
+impl ChildOutput {
+    /// The kind of a failure of this child: `otherwise`, unless the kernel
+    /// stopped it.
+    fn failure_kind(&self, otherwise: ErrorKind) -> ErrorKind {
+        if self.timed_out {
+            ErrorKind::Timeout
+        } else if self.interrupted {
+            ErrorKind::Interrupted
+        } else {
+            otherwise
+        }
+    }
+}
+
+/// Cap the calling process's address space at `bytes`.  Run in the child
+/// between fork and exec, so allocations beyond the limit fail in the cell's
+/// program instead of exhausting the machine.
+#[cfg(unix)]
+fn limit_address_space(bytes: u64) -> std::io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: bytes as libc::rlim_t,
+        rlim_max: bytes as libc::rlim_t,
+    };
+    // SAFETY: `limit` is a valid rlimit for the duration of the call.
+    if unsafe { libc::setrlimit(libc::RLIMIT_AS, &limit) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// A job object limiting the memory of the process assigned to it; closed
+/// when dropped.  The process is assigned just after it starts, so it runs
+/// unlimited for that moment.
+#[cfg(windows)]
+struct MemoryLimitJob(windows_sys::Win32::Foundation::HANDLE);
+
+#[cfg(windows)]
+impl MemoryLimitJob {
+    fn assign(child: &std::process::Child, bytes: u64) -> Option<Self> {
+        use std::os::windows::io::AsRawHandle;
+        use windows_sys::Win32::System::JobObjects::{
+            AssignProcessToJobObject, CreateJobObjectW, JobObjectExtendedLimitInformation,
+            SetInformationJobObject, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+            JOB_OBJECT_LIMIT_PROCESS_MEMORY,
+        };
+
+        // SAFETY: plain Win32 calls on a job handle owned here and the
+        // child's live process handle; `info` outlives the call using it.
+        unsafe {
+            let job = MemoryLimitJob(CreateJobObjectW(std::ptr::null(), std::ptr::null()));
+            if job.0 == 0 {
+                log!("Cannot create a job object; no memory limit applied");
+                return None;
+            }
+            let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = std::mem::zeroed();
+            info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_PROCESS_MEMORY;
+            info.ProcessMemoryLimit = bytes as usize;
+            let set = SetInformationJobObject(
+                job.0,
+                JobObjectExtendedLimitInformation,
+                &info as *const _ as *const std::ffi::c_void,
+                std::mem::size_of_val(&info) as u32,
+            );
+            if set == 0 || AssignProcessToJobObject(job.0, child.as_raw_handle() as _) == 0 {
+                log!("Cannot apply the memory limit: {}", std::io::Error::last_os_error());
+                return None;
+            }
+            Some(job)
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for MemoryLimitJob {
+    fn drop(&mut self) {
+        // SAFETY: the handle came from CreateJobObjectW and is closed once.
+        unsafe {
+            windows_sys::Win32::Foundation::CloseHandle(self.0);
+        }
+    }
+}
+
+/// Whether a failed program's stderr reports that an allocation failed.
+fn is_out_of_memory(stderr: &str) -> bool {
+    let stderr = stderr.to_ascii_lowercase();
+    ["out of memory", "cannot allocate", "malloc", "memory allocation failed"]
+        .iter()
+        .any(|sign| stderr.contains(sign))
+}
+
+/// Wait for `child` for at most `limit`, stopping it (and its process group)
+/// if it is still running then.  Returns the exit status and whether the
+/// limit was hit.
+fn wait_with_timeout(
+    child: &mut std::process::Child,
+    limit: Duration,
+) -> std::io::Result<(std::process::ExitStatus, bool)> {
+    let deadline = Instant::now() + limit;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok((status, false));
+        }
+        if Instant::now() >= deadline {
+            kill_process_group(child.id());
+            child.kill().ok();
+            return child.wait().map(|status| (status, true));
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Read `pipe` to EOF on a background thread, forwarding chunks as they
+/// arrive.  The channel disconnects once the pipe closes.
+fn spawn_pipe_reader(pipe: Option<impl Read + Send + 'static>) -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+    if let Some(mut pipe) = pipe {
+        thread::spawn(move || {
+            let mut buf = [0u8; 8192];
+            loop {
+                match pipe.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if tx.send(buf[..n].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+    }
+    rx
+}
+
+/// Stream plain stdout lines from `rx` to `live`, sending whatever arrived
+/// within `window` (or `stream_chunk_bytes`, if sooner) as one message.
+/// `vkernel` helper lines are held back and passed on through the returned
+/// channel, like all output is without streaming, so they are still parsed
+/// when the cell finishes.  Streamed lines are not passed on, so they are not
+/// published twice.  Partial lines wait for their newline.
+fn coalesce_stdout(
+    rx: mpsc::Receiver<Vec<u8>>,
+    live: mpsc::Sender<LiveOutput>,
+    window: Duration,
+    config: &Config,
+) -> mpsc::Receiver<Vec<u8>> {
+    let (tx, held) = mpsc::channel();
+    let (max_bytes, encoding) = (config.stream_chunk_bytes, config.output_encoding);
+    thread::spawn(move || {
+        let mut partial: Vec<u8> = Vec::new();
+        let mut pending = String::new();
+        let mut since: Option<Instant> = None;
+        let flush = |pending: &mut String| {
+            if !pending.is_empty() {
+                let text = std::mem::take(pending);
+                live.send(LiveOutput { msg_type: "stream", content: json!({ "name": "stdout", "text": text }) })
+                    .ok();
+            }
+        };
+        loop {
+            let received = match since {
+                Some(t) => rx.recv_timeout((t + window).saturating_duration_since(Instant::now())),
+                None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            };
+            let done = match received {
+                Ok(chunk) => {
+                    partial.extend_from_slice(&chunk);
+                    false
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    flush(&mut pending);
+                    since = None;
+                    continue;
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    if !partial.is_empty() && partial.last() != Some(&b'\n') {
+                        partial.push(b'\n');
+                    }
+                    true
+                }
+            };
+            while let Some(end) = partial.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = partial.drain(..=end).collect();
+                let text = decode_output(&line, encoding);
+                if is_helper_line(&text) {
+                    tx.send(line).ok();
+                } else {
+                    pending.push_str(&text);
+                    since.get_or_insert_with(Instant::now);
+                }
+            }
+            if done {
+                flush(&mut pending);
+                break;
+            }
+            if pending.len() >= max_bytes {
+                flush(&mut pending);
+                since = None;
+            }
+        }
+    });
+    held
+}
+
+/// Collect everything `spawn_pipe_reader` has read, waiting until `deadline`
+/// for the pipe to close.  Returns the bytes and whether EOF was reached.
+fn drain_pipe(rx: &mpsc::Receiver<Vec<u8>>, deadline: Instant) -> (Vec<u8>, bool) {
+    let mut out = Vec::new();
+    loop {
+        match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(chunk) => out.extend_from_slice(&chunk),
+            Err(mpsc::RecvTimeoutError::Disconnected) => return (out, true),
+            Err(mpsc::RecvTimeoutError::Timeout) => return (out, false),
+        }
+    }
+}
+
+/// Replace occurrences of the temp cell filename in `text` with `line N`.
+///
+/// The V compiler emits paths in one of two forms:
+///   /full/path/to/cell_3.v:7:5: error: …      (absolute path)
+///   cell_3.v:7:5: error: …                    (basename only)
+///
+/// Both are replaced with `line 7:5: error: …` so error messages make
+/// sense in the context of the cell the user just executed.
+fn rewrite_cell_paths(text: &str, src: &Path) -> String {
+    // Build the two patterns to replace: full path and basename.
+    let full = src.to_string_lossy().to_string();
+    let basename = src
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    // Replace full path first (it subsumes the basename on most systems),
+    // then any remaining basename-only occurrences.
+    let step1 = if !full.is_empty() {
+        text.replace(&full, "cell")
+    } else {
+        text.to_string()
+    };
+    let step2 = if !basename.is_empty() && basename != full {
+        step1.replace(&basename, "cell")
+    } else {
+        step1
+    };
+
+    // Now rewrite "cell:LINE:COL:" → "line LINE:COL:" and "cell:LINE:" → "line LINE:"
+    // The V compiler always separates the location with `:` so a simple
+    // prefix replacement on `cell:` is sufficient.
+    step2.replace("cell:", "line ")
+}
+
+// ── Process interrupt ───────────────────────────────────────────────────────
+
+/// `kernel.json` declares `interrupt_mode: "message"`, so interrupts arrive as
+/// `interrupt_request` on the control socket and are forwarded to the running
+/// child by `interrupt_process`.  A front-end that signals the kernel process
+/// anyway must not kill it, so SIGINT gets a no-op handler.
+///
+/// A handler rather than `SIG_IGN`: ignored dispositions are inherited across
+/// exec, which would make the child processes deaf to our own SIGINT.
+fn install_signal_handlers() {
+    #[cfg(unix)]
+    {
+        extern "C" fn on_sigint(_: libc::c_int) {}
+        unsafe {
+            libc::signal(libc::SIGINT, on_sigint as *const () as libc::sighandler_t);
+            // A reader closing the other end of a pipe (Zed dropping the
+            // kernel's stderr, say) must not kill the kernel: ignored, the
+            // write fails with EPIPE and is dealt with where it happens.
+            // The Rust runtime already does this; don't depend on it.
+            // Cell programs get the default back from `Command`.
+            libc::signal(libc::SIGPIPE, libc::SIG_IGN);
+        }
+    }
+}
+
+fn interrupt_process(pid: u32) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    // `run_child` makes each child a process group leader, so the signal
+    // reaches anything the child forked as well.
+    #[cfg(unix)]
+    {
+        unsafe {
+            libc::kill(-(pid as libc::pid_t), libc::SIGINT);
+        }
+    }
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::Foundation::CloseHandle;
         use windows_sys::Win32::System::Threading::{
             OpenProcess, TerminateProcess, PROCESS_TERMINATE,
         };
-        unsafe {
-            let handle = OpenProcess(PROCESS_TERMINATE, 0, pid);
-            if handle != 0 {
-                TerminateProcess(handle, 1);
-                CloseHandle(handle);
+        unsafe {
+            let handle = OpenProcess(PROCESS_TERMINATE, 0, pid);
+            if handle != 0 {
+                TerminateProcess(handle, 1);
+                CloseHandle(handle);
+            }
+        }
+    }
+}
+
+/// Set by `interrupt_process` so `run_child` can tell an interrupted child
+/// from one that failed by itself.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// ── Shutdown ──────────────────────────────────────────────────────────────────
+
+/// Kill what remains of the process group led by `pid`.  Windows has no
+/// process groups to speak of, so there this is a no-op.
+fn kill_process_group(pid: u32) {
+    #[cfg(unix)]
+    {
+        unsafe {
+            libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = pid;
+}
+
+/// Set by `shutdown_kernel` to stop the heartbeat thread.
+static HEARTBEAT_STOP: AtomicBool = AtomicBool::new(false);
+
+/// The heartbeat thread, joined by `shutdown_kernel`.
+static HEARTBEAT: Mutex<Option<thread::JoinHandle<()>>> = Mutex::new(None);
+
+/// How often the heartbeat thread checks `HEARTBEAT_STOP` while no ping is
+/// waiting.  Also bounds how long `shutdown_kernel` waits for it.
+const HEARTBEAT_POLL: Duration = Duration::from_millis(100);
+
+/// Echo heartbeat pings until `HEARTBEAT_STOP` is set, then unbind.  Closing
+/// the socket explicitly matters for the `ipc` transport, where a socket file
+/// left behind by an abrupt exit can stop the next kernel from binding.
+fn run_heartbeat(heartbeat: Socket, endpoint: &str) {
+    heartbeat.set_rcvtimeo(HEARTBEAT_POLL.as_millis() as i32).ok();
+    heartbeat.set_linger(0).ok();
+    while !HEARTBEAT_STOP.load(Ordering::SeqCst) {
+        if let Ok(msg) = heartbeat.recv_bytes(0) {
+            heartbeat.send(&msg, 0).ok();
+        }
+    }
+    heartbeat.unbind(endpoint).ok();
+    drop(heartbeat);
+    // The unbind completes on ZeroMQ's I/O thread, which may not get to it
+    // before the process exits; remove the socket file ourselves.
+    if let Some(path) = endpoint.strip_prefix("ipc://") {
+        fs::remove_file(path).ok();
+    }
+}
+
+/// Stop any running child, remove the session's temp directory (unless
+/// `tmp_dir` is `None`, for `V_KERNEL_KEEP_TMP`) and exit.
+///
+/// `process::exit` skips destructors, so the cleanup normally done by
+/// `KernelState::drop` has to happen explicitly here.  Neither argument needs
+/// the state lock, so this works even while a cell is executing.
+fn shutdown_kernel(running_pid: &Mutex<Option<u32>>, tmp_dir: Option<&Path>, code: i32) -> ! {
+    if let Some(pid) = *running_pid.lock().unwrap() {
+        interrupt_process(pid);
+        kill_process_group(pid);
+    }
+    HEARTBEAT_STOP.store(true, Ordering::SeqCst);
+    if let Some(heartbeat) = HEARTBEAT.lock().unwrap().take() {
+        heartbeat.join().ok();
+    }
+    if let Some(tmp_dir) = tmp_dir {
+        fs::remove_dir_all(tmp_dir).ok();
+    }
+    std::process::exit(code);
+}
+
+// ── Completion ────────────────────────────────────────────────────────────────
+
+/// V keywords offered by `complete_request` alongside session symbols.
+const V_KEYWORDS: &[&str] = &[
+    "as", "asm", "assert", "atomic", "break", "const", "continue", "defer", "else", "enum",
+    "false", "fn", "for", "go", "goto", "if", "import", "in", "interface", "is", "isreftype",
+    "lock", "match", "module", "mut", "none", "or", "pub", "return", "rlock", "select",
+    "shared", "sizeof", "spawn", "static", "struct", "true", "type", "typeof", "union",
+    "unsafe", "volatile", "__global", "__offsetof",
+];
+
+/// A completion candidate: name, kind and a one-line signature.
+struct Symbol {
+    name: String,
+    kind: &'static str,
+    signature: String,
+}
+
+/// Symbols introduced by the accumulated declarations.
+fn session_symbols(declarations: &[String]) -> Vec<Symbol> {
+    let mut symbols = Vec::new();
+    for decl in declarations {
+        // Skip attribute lines to reach the declaration itself.
+        let Some(head) = decl.lines().map(str::trim).find(|l| !is_attribute_line(l)) else {
+            continue;
+        };
+        let signature = head.trim_end_matches('{').trim_end().to_string();
+        let stripped = strip_modifiers(strip_attributes(head));
+        let kind = match stripped.split_whitespace().next().unwrap_or("") {
+            "fn" if stripped[2..].trim_start().starts_with('(') => "method",
+            "fn" => "function",
+            "struct" => "struct",
+            "union" => "union",
+            "enum" => "enum",
+            "interface" => "interface",
+            "type" => "type",
+            "const" | "const(" => "constant",
+            "__global" | "__global(" => "global",
+            _ => continue,
+        };
+        match decl_name(stripped) {
+            Some(name) => symbols.push(Symbol {
+                name: name.to_string(),
+                kind,
+                signature,
+            }),
+            // `const ( a = 1 … )` blocks: one symbol per line.
+            None if kind == "constant" || kind == "global" => {
+                for line in decl.lines().skip(1) {
+                    let Some((name, _)) = line.split_once('=') else {
+                        continue;
+                    };
+                    let name = name.split_whitespace().next().unwrap_or("");
+                    if !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                        symbols.push(Symbol {
+                            name: name.to_string(),
+                            kind,
+                            signature: line.trim().to_string(),
+                        });
+                    }
+                }
+            }
+            None => {}
+        }
+    }
+    symbols
+}
+
+/// Whether `stmts` may depend on a non-import declaration in `declarations`:
+/// they mention one of its names, or it is something `session_symbols` does
+/// not understand (and so cannot be ruled out).  Errs towards `true`, which
+/// only costs a full build.
+fn references_session(declarations: &[String], stmts: &[String]) -> bool {
+    let idents = identifiers(stmts);
+    declarations
+        .iter()
+        .filter(|d| !d.trim_start().starts_with("import "))
+        .any(|decl| {
+            let symbols = session_symbols(std::slice::from_ref(decl));
+            symbols.is_empty() || symbols.iter().any(|s| idents.contains(s.name.as_str()))
+        })
+}
+
+/// Every identifier-like word in `stmts`, string contents included (they may
+/// interpolate `${name}`).
+fn identifiers(stmts: &[String]) -> HashSet<&str> {
+    stmts
+        .iter()
+        .flat_map(|s| s.split(|c: char| !(c.is_alphanumeric() || c == '_')))
+        .filter(|w| !w.is_empty())
+        .collect()
+}
+
+/// Build a `complete_reply` for the identifier ending at `cursor_pos`
+/// (counted in Unicode code points, per the protocol).
+///
+/// Matches come from session declarations, then V keywords.  Each match's
+/// kind and signature go in `metadata._jupyter_types_experimental` for
+/// front-ends that can show them; `matches` alone works everywhere else.
+fn complete(code: &str, cursor_pos: usize, declarations: &[String]) -> Value {
+    let chars: Vec<char> = code.chars().collect();
+    let end = cursor_pos.min(chars.len());
+    let mut start = end;
+    while start > 0 && (chars[start - 1].is_alphanumeric() || chars[start - 1] == '_') {
+        start -= 1;
+    }
+    let prefix: String = chars[start..end].iter().collect();
+
+    let mut seen = std::collections::HashSet::new();
+    let mut matches = Vec::new();
+    let mut types = Vec::new();
+    let keywords = V_KEYWORDS.iter().map(|k| Symbol {
+        name: k.to_string(),
+        kind: "keyword",
+        signature: String::new(),
+    });
+    for symbol in session_symbols(declarations).into_iter().chain(keywords) {
+        if !symbol.name.starts_with(&prefix) || !seen.insert(symbol.name.clone()) {
+            continue;
+        }
+        types.push(json!({
+            "start": start,
+            "end": end,
+            "text": symbol.name,
+            "type": symbol.kind,
+            "signature": symbol.signature
+        }));
+        matches.push(symbol.name);
+    }
+
+    json!({
+        "status": "ok",
+        "matches": matches,
+        "cursor_start": start,
+        "cursor_end": end,
+        "metadata": { "_jupyter_types_experimental": types }
+    })
+}
+
+// ── Kernel info ───────────────────────────────────────────────────────────────
+
+/// The crate version, plus the short git commit when the build script
+/// could determine it (`0.1.0+abc1234`).
+fn kernel_version() -> String {
+    let version = env!("CARGO_PKG_VERSION");
+    match option_env!("V_KERNEL_GIT_COMMIT") {
+        Some(commit) => format!("{version}+{commit}"),
+        None => version.to_string(),
+    }
+}
+
+/// `kernel_info_reply` content.
+///
+/// The capability fields tell front-ends which optional UI to offer; keep
+/// them in sync with the requests `handle_shell` / `handle_control` answer.
+fn kernel_info_content() -> Value {
+    json!({
+        "status": "ok",
+        "protocol_version": "5.3",
+        "implementation": "v-kernel",
+        "implementation_version": kernel_version(),
+        // No debug_request handling.
+        "debugger": false,
+        // JEP 92 optional features ("debugger", "kernel subshells"): none.
+        "supported_features": [],
+        // Per-request detail for front-ends that look for it.
+        "features": {
+            "completion": true,
+            "inspection": false,
+            "is_complete": true,
+            "history": false,
+            "debugger": false,
+            "interrupt": "message"
+        },
+        "language_info": {
+            "name": "v",
+            "version": "0.4",
+            "mimetype": "text/x-vlang",
+            "file_extension": ".v",
+            "pygments_lexer": "v",
+            "codemirror_mode": "clike"
+        },
+        "banner": "V kernel for Zed — stateful REPL powered by v-kernel",
+        "help_links": [
+            {
+                "text": "V Documentation",
+                "url": "https://docs.vlang.io/"
+            }
+        ]
+    })
+}
+
+// ── Kernel event loop ─────────────────────────────────────────────────────────
+
+/// How long one `zmq::poll` waits before the loop checks for finished cells.
+const POLL_INTERVAL_MS: i64 = 20;
+
+/// A cell handed to the execution worker.
+struct ExecJob {
+    request: JupyterMessage,
+    code: String,
+    /// The cell is tagged `init`: its declarations survive a restart.
+    init: bool,
+}
+
+/// An IOPub message from the worker, published while the cell still runs.
+struct LiveOutput {
+    msg_type: &'static str,
+    content: Value,
+}
+
+/// Sent back by the execution worker when a cell has finished.
+struct ExecDone {
+    request: JupyterMessage,
+    output: ExecOutput,
+    execution_count: u32,
+}
+
+/// Owns every socket except heartbeat and handles all shell, control and
+/// stdin traffic on a single thread.  Only cell execution runs elsewhere, on
+/// the worker thread, so message handling is strictly serialised and IOPub
+/// needs no lock.
+struct Kernel {
+    config: Config,
+    key: Vec<u8>,
+    session_id: String,
+    shell: Socket,
+    control: Socket,
+    iopub: Socket,
+    stdin: Option<Socket>,
+    state: Arc<Mutex<KernelState>>,
+    running_pid: Arc<Mutex<Option<u32>>>,
+    /// Removed on shutdown; `None` with `V_KERNEL_KEEP_TMP`.
+    tmp_dir: Option<PathBuf>,
+    jobs: mpsc::Sender<ExecJob>,
+    done: mpsc::Receiver<ExecDone>,
+    /// Output streamed by the running cell (see `KernelState::send_live`).
+    live: mpsc::Receiver<LiveOutput>,
+    /// The request whose output `live` carries; `None` for silent requests,
+    /// whose live output is discarded.
+    live_parent: Option<JupyterMessage>,
+    /// True while the worker is executing a cell.  Shell requests stay queued
+    /// in ZeroMQ until it finishes; control requests are still served.
+    busy: bool,
+    /// Set when an IOPub send fails; further IOPub messages are dropped
+    /// until the next request starts.  Replies are still sent.
+    iopub_failed: Cell<bool>,
+}
+
+impl Kernel {
+    fn run(&mut self) -> ! {
+        loop {
+            let (control_ready, shell_ready, stdin_ready) = {
+                let mut items = vec![self.control.as_poll_item(zmq::POLLIN)];
+                if !self.busy {
+                    items.push(self.shell.as_poll_item(zmq::POLLIN));
+                }
+                if let Some(stdin) = &self.stdin {
+                    items.push(stdin.as_poll_item(zmq::POLLIN));
+                }
+                zmq::poll(&mut items, POLL_INTERVAL_MS).ok();
+                let mut ready = items.iter().map(|i| i.is_readable());
+                let control = ready.next().unwrap_or(false);
+                let shell = !self.busy && ready.next().unwrap_or(false);
+                let stdin = ready.next().unwrap_or(false);
+                (control, shell, stdin)
+            };
+
+            if control_ready {
+                if let Some(msg) = recv_message(&self.control, &self.key, &self.config) {
+                    self.touch();
+                    self.handle_control(msg);
+                }
+            }
+            if shell_ready {
+                if let Some(msg) = recv_message(&self.shell, &self.key, &self.config) {
+                    self.touch();
+                    self.handle_shell(msg);
+                }
+            }
+            if stdin_ready {
+                // V cells never send input_request, so anything arriving here
+                // is unsolicited.  Drain it so it cannot pile up.
+                if let Some(stdin) = &self.stdin {
+                    if let Some(msg) = recv_message(stdin, &self.key, &self.config) {
+                        log!("Ignoring stdin msg: {}", msg_type(&msg));
+                    }
+                }
+            }
+
+            self.publish_live();
+            while let Ok(done) = self.done.try_recv() {
+                // Whatever the cell streamed goes out before its result.
+                self.publish_live();
+                self.finish_execution(done);
+            }
+        }
+    }
+
+    /// Publish the running cell's streamed output.
+    fn publish_live(&self) {
+        while let Ok(output) = self.live.try_recv() {
+            if let Some(parent) = &self.live_parent {
+                self.publish(parent, output.msg_type, output.content);
+            }
+        }
+    }
+
+    /// Record activity for the idle watchdog.  `try_lock` because the worker
+    /// holds the state lock while a cell runs, and a busy kernel is not idle.
+    fn touch(&self) {
+        if let Ok(mut s) = self.state.try_lock() {
+            s.touch();
+        }
+    }
+
+    // ── Sending ───────────────────────────────────────────────────────────────
+
+    /// Send a reply to `parent` on `socket` (shell or control).
+    fn reply(&self, socket: &Socket, parent: &JupyterMessage, msg_type: &str, content: Value) {
+        self.reply_with_metadata(socket, parent, msg_type, content, json!({}));
+    }
+
+    fn reply_with_metadata(
+        &self,
+        socket: &Socket,
+        parent: &JupyterMessage,
+        msg_type: &str,
+        content: Value,
+        metadata: Value,
+    ) {
+        let reply = JupyterMessage {
+            identities: parent.identities.clone(),
+            header: make_header(msg_type, &self.session_id, &self.config.username),
+            parent_header: parent.header.clone(),
+            metadata,
+            content,
+            buffers: vec![],
+        };
+        if let Err(e) = send_message(socket, &reply, &self.key) {
+            log!("Failed to send {msg_type}: {e}");
+        }
+    }
+
+    /// Broadcast a message on IOPub on behalf of `parent`.
+    fn publish(&self, parent: &JupyterMessage, msg_type: &str, content: Value) {
+        self.publish_with_metadata(parent, msg_type, content, json!({}));
+    }
+
+    fn publish_with_metadata(
+        &self,
+        parent: &JupyterMessage,
+        msg_type: &str,
+        content: Value,
+        metadata: Value,
+    ) {
+        let msg = JupyterMessage {
+            identities: vec![],
+            header: make_header(msg_type, &self.session_id, &self.config.username),
+            parent_header: parent.header.clone(),
+            metadata,
+            content,
+            buffers: vec![],
+        };
+        // After one failure the front-end is most likely gone; skip the rest
+        // of this cell's IOPub traffic rather than failing on every message.
+        if self.iopub_failed.get() {
+            return;
+        }
+        if let Err(e) = send_message(&self.iopub, &msg, &self.key) {
+            log!("IOPub send of {msg_type} failed ({e}) — dropping IOPub output until the next request");
+            self.iopub_failed.set(true);
+        }
+    }
+
+    fn publish_status(&self, parent: &JupyterMessage, execution_state: &str) {
+        self.publish(parent, "status", json!({ "execution_state": execution_state }));
+    }
+
+    /// Publish a `display_data` bundle, keeping only the MIME types allowed by
+    /// `Config::mime_types`.  `text/plain` is always kept as the fallback.
+    fn publish_display(&self, parent: &JupyterMessage, mut data: Value) {
+        if let (Some(allowed), Some(bundle)) = (&self.config.mime_types, data.as_object_mut()) {
+            bundle.retain(|mime, _| mime == "text/plain" || allowed.iter().any(|a| a == mime));
+        }
+        self.publish(parent, "display_data", json!({ "data": data, "metadata": {} }));
+    }
+
+    fn publish_stream(&self, parent: &JupyterMessage, name: &str, text: &str) {
+        self.publish(parent, "stream", json!({ "name": name, "text": text }));
+    }
+
+    // ── Control channel ───────────────────────────────────────────────────────
+
+    fn handle_control(&mut self, msg: JupyterMessage) {
+        match msg_type(&msg) {
+            "shutdown_request" => {
+                let restart = msg.content["restart"].as_bool().unwrap_or(false);
+                log!("Shutdown requested. restart={restart}");
+                if !restart {
+                    self.reply(
+                        &self.control,
+                        &msg,
+                        "shutdown_reply",
+                        json!({ "status": "ok", "restart": false }),
+                    );
+                    shutdown_kernel(&self.running_pid, self.tmp_dir.as_deref(), 0);
+                }
+                // Restart in place: kill whatever is running so the worker
+                // lets go of the state, then start a fresh session from the
+                // `init`-tagged declarations.
+                if let Some(pid) = *self.running_pid.lock().unwrap() {
+                    interrupt_process(pid);
+                    kill_process_group(pid);
+                }
+                let content = match lock_within(&self.state, RESTART_WAIT) {
+                    Some(mut state) => {
+                        let restored = state.restart();
+                        log!("Session restarted; re-applied {restored} init declaration(s)");
+                        json!({ "status": "ok", "restart": true })
+                    }
+                    None => {
+                        let evalue = format!(
+                            "the running cell did not stop within {}s; interrupt it and try again",
+                            RESTART_WAIT.as_secs()
+                        );
+                        log!("Restart failed: {evalue}");
+                        json!({
+                            "status": "error",
+                            "restart": true,
+                            "ename": "RestartError",
+                            "evalue": evalue,
+                            "traceback": [],
+                        })
+                    }
+                };
+                self.reply(&self.control, &msg, "shutdown_reply", content);
+            }
+            "interrupt_request" => {
+                let pid = *self.running_pid.lock().unwrap();
+                if let Some(pid) = pid {
+                    interrupt_process(pid);
+                    log!("Interrupted pid={pid}");
+                } else {
+                    log!("interrupt_request but no child running");
+                }
+                self.reply(&self.control, &msg, "interrupt_reply", json!({ "status": "ok" }));
+            }
+            other => {
+                log!("Unhandled control msg: {other}");
+            }
+        }
+    }
+
+    // ── Shell channel ─────────────────────────────────────────────────────────
+
+    fn handle_shell(&mut self, msg: JupyterMessage) {
+        let msg_type = msg_type(&msg).to_string();
+        log!("shell <- {msg_type}");
+        // Give IOPub another chance: the front-end may have reconnected.
+        self.iopub_failed.set(false);
+
+        match msg_type.as_str() {
+            "kernel_info_request" => {
+                self.reply(&self.shell, &msg, "kernel_info_reply", kernel_info_content());
+            }
+
+            "execute_request" => self.start_execution(msg),
+
+            "complete_request" => {
+                let code = msg.content["code"].as_str().unwrap_or("");
+                let cursor_pos = msg.content["cursor_pos"].as_u64().unwrap_or(0) as usize;
+                let content = {
+                    let state = self.state.lock().unwrap();
+                    complete(code, cursor_pos, &state.declarations)
+                };
+                self.reply(&self.shell, &msg, "complete_reply", content);
+            }
+
+            "is_complete_request" => {
+                self.reply(&self.shell, &msg, "is_complete_reply", json!({ "status": "complete" }));
+            }
+
+            "comm_info_request" => {
+                self.reply(
+                    &self.shell,
+                    &msg,
+                    "comm_info_reply",
+                    json!({ "status": "ok", "comms": {} }),
+                );
+            }
+
+            "history_request" => {
+                self.reply(
+                    &self.shell,
+                    &msg,
+                    "history_reply",
+                    json!({ "status": "ok", "history": [] }),
+                );
+            }
+
+            other => {
+                log!("Unhandled shell msg type: {other}");
+            }
+        }
+    }
+
+    /// Announce an execute_request and hand it to the worker.  The reply is
+    /// sent later, from `finish_execution`.
+    fn start_execution(&mut self, msg: JupyterMessage) {
+        let (code, unreadable) = match request_code(&msg) {
+            Ok(code) => (code, None),
+            Err(e) => (String::new(), Some(ExecOutput::error(e))),
+        };
+        let silent = msg.content["silent"].as_bool().unwrap_or(false);
+
+        if !silent {
+            let exec_count = self.state.lock().unwrap().execution_count + 1;
+            self.publish_status(&msg, "busy");
+            self.publish(
+                &msg,
+                "execute_input",
+                json!({
+                    "code": code,
+                    "execution_count": exec_count
+                }),
+            );
+        }
+
+        // A blank cell still counts as an execution, but there is nothing
+        // to compile — answer it right here instead of going to the worker.
+        // So does a cell whose file could not be read.
+        if unreadable.is_some() || code.trim().is_empty() {
+            let execution_count = {
+                let mut state = self.state.lock().unwrap();
+                state.execution_count += 1;
+                state.execution_count
+            };
+            self.finish_execution(ExecDone {
+                request: msg,
+                output: unreadable.unwrap_or_default(),
+                execution_count,
+            });
+            return;
+        }
+
+        self.busy = true;
+        self.live_parent = (!silent).then(|| msg.clone());
+        let init = has_tag(&msg, "init");
+        if self.jobs.send(ExecJob { request: msg, code, init }).is_err() {
+            log!("Execution worker is gone — exiting");
+            shutdown_kernel(&self.running_pid, self.tmp_dir.as_deref(), 1);
+        }
+    }
+
+    /// Publish a finished cell's output, send its execute_reply and go idle.
+    fn finish_execution(&mut self, done: ExecDone) {
+        self.busy = false;
+        self.live_parent = None;
+        let ExecDone {
+            request: msg,
+            output,
+            execution_count,
+        } = done;
+        let ExecOutput {
+            stdout: raw_stdout,
+            stderr,
+            diagnostics,
+            error,
+            payload,
+            exit_code,
+            compile_time,
+            run_time,
+            clear_output,
+            timeout,
+            diagnostics_sent,
+        } = output;
+        let is_error = error.is_some();
+        let silent = msg.content["silent"].as_bool().unwrap_or(false);
+
+        // ── Split dump() lines from stdout AND stderr ─────────────────────────
+        // V writes dump() output to stderr (not stdout). We intercept
+        // dump lines from both streams and merge them into a single
+        // HTML table, emitted before the plain text output.
+        let DisplayOutput {
+            plain: raw_stdout,
+            items: display_items,
+            results,
+            errors: display_errors,
+        } = split_display_output(&raw_stdout);
+        let (plain_stdout, mut dump_entries) = split_dump_output(&raw_stdout);
+        let (plain_stderr, stderr_dump_entries) = if !is_error {
+            split_dump_output(&stderr)
+        } else {
+            // Don't strip dump lines from a genuine compiler error —
+            // the whole stderr is the error message.
+            (stderr.clone(), vec![])
+        };
+        dump_entries.extend(stderr_dump_entries);
+
+        // A failed assert is reported as such whether V exited or panicked.
+        let assertion = matches!(error, Some(ErrorKind::RuntimeError | ErrorKind::VPanic))
+            .then(|| parse_assert_failure(&stderr))
+            .flatten();
+        let error_content = match (&assertion, error) {
+            (Some(a), _) => json!({
+                "ename": ErrorKind::AssertionError.ename(),
+                "evalue": a.expr,
+                "traceback": a.traceback
+            }),
+            (None, kind) => {
+                let kind = kind.unwrap_or(ErrorKind::Internal);
+                json!({
+                    "ename": kind.ename(),
+                    "evalue": kind.description(),
+                    "traceback": stderr.lines().collect::<Vec<_>>()
+                })
+            }
+        };
+
+        if !silent {
+            if clear_output {
+                self.publish(&msg, "clear_output", json!({ "wait": false }));
+            }
+
+            // Compiler warnings go ahead of the program's output
+            if !diagnostics.is_empty() && !diagnostics_sent {
+                self.publish_stream(&msg, "stderr", &diagnostics);
+            }
+
+            // Plain stdout stream (non-dump lines)
+            if !plain_stdout.is_empty() {
+                self.publish_stream(&msg, "stdout", &plain_stdout);
+            }
+
+            // dump() entries as rich HTML display_data
+            if !dump_entries.is_empty() {
+                let html = render_dump_table(&dump_entries);
+                // Plain-text fallback for non-HTML frontends.
+                let plain_fallback = dump_entries
+                    .iter()
+                    .map(|e| {
+                        if e.typ.is_empty() {
+                            format!("[{}] {}: {}", e.location, e.name, e.value)
+                        } else {
+                            format!("[{}] {} = {}({})", e.location, e.name, e.typ, e.value)
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                self.publish_display(
+                    &msg,
+                    json!({
+                        "text/html": html,
+                        "text/plain": plain_fallback
+                    }),
+                );
             }
+
+            // vkernel helper output as display_data
+            for item in &display_items {
+                // LaTeX source reads fine as plain text; other types get a
+                // placeholder.
+                let plain = match (&item.data, item.mime.as_str()) {
+                    (Value::String(latex), "text/latex") => latex.clone(),
+                    _ => format!("<{} output>", item.mime),
+                };
+                let mut bundle = json!({ "text/plain": plain });
+                bundle[item.mime.as_str()] = item.data.clone();
+                self.publish_display(&msg, bundle);
+            }
+            if !display_errors.is_empty() {
+                let text: String = display_errors
+                    .iter()
+                    .map(|e| format!("[v-kernel] display: {e}\n"))
+                    .collect();
+                self.publish_stream(&msg, "stderr", &text);
+            }
+
+            // Echoed values as a single execute_result.  Statements are never
+            // re-run, so every result line came from this cell; several
+            // (`return`, echoed assignments, `%p`) are joined rather than
+            // published as separate results for one execution_count.
+            if !results.is_empty() {
+                self.publish(
+                    &msg,
+                    "execute_result",
+                    json!({
+                        "execution_count": execution_count,
+                        "data": { "text/plain": results.join("\n") },
+                        "metadata": {}
+                    }),
+                );
+            }
+
+            // stderr / error
+            if is_error {
+                // full stderr for error messages, minus a failed assert that
+                // the error itself already shows
+                let rest = assertion.as_ref().map_or(stderr.as_str(), |a| a.rest.as_str());
+                if !rest.trim().is_empty() {
+                    self.publish_stream(&msg, "stderr", rest);
+                }
+                self.publish(&msg, "error", error_content.clone());
+            } else if !plain_stderr.is_empty() {
+                // dump lines stripped
+                self.publish_stream(&msg, "stderr", &plain_stderr);
+            }
+        }
+
+        let reply_content = if is_error {
+            let mut content = error_content;
+            content["status"] = json!("error");
+            content["execution_count"] = json!(execution_count);
+            content
+        } else {
+            json!({
+                "status": "ok",
+                "execution_count": execution_count,
+                "payload": payload,
+                "user_expressions": {}
+            })
+        };
+        // Compact outcome summary so front-ends can badge the cell without
+        // parsing its output.
+        let summary = json!({
+            "v_kernel": {
+                "warnings": count_diagnostics(&diagnostics, "warning"),
+                "errors": if is_error { count_diagnostics(&stderr, "error").max(1) } else { 0 },
+                "exit_code": exit_code,
+                "compile_ms": compile_time.map(|t| t.as_millis() as u64),
+                "run_ms": run_time.map(|t| t.as_millis() as u64),
+                "timeout_s": timeout.map(|t| t.as_secs_f64())
+            }
+        });
+        self.reply_with_metadata(&self.shell, &msg, "execute_reply", reply_content, summary.clone());
+
+        if !silent {
+            self.publish_with_metadata(&msg, "status", json!({ "execution_state": "idle" }), summary);
         }
     }
 }
 
-// ── Kernel info ───────────────────────────────────────────────────────────────
+/// Whether the request's metadata carries cell tag `tag` (`metadata.tags`).
+fn has_tag(msg: &JupyterMessage, tag: &str) -> bool {
+    msg.metadata["tags"]
+        .as_array()
+        .is_some_and(|tags| tags.iter().any(|t| t.as_str() == Some(tag)))
+}
 
-fn kernel_info_content() -> Value {
-    json!({
-        "status": "ok",
-        "protocol_version": "5.3",
-        "implementation": "v-kernel",
-        "implementation_version": "0.1.0",
-        "language_info": {
-            "name": "v",
-            "version": "0.4",
-            "mimetype": "text/x-vlang",
-            "file_extension": ".v",
-            "pygments_lexer": "v",
-            "codemirror_mode": "clike"
-        },
-        "banner": "V kernel for Zed — stateful REPL powered by v-kernel",
-        "help_links": [
-            {
-                "text": "V Documentation",
-                "url": "https://docs.vlang.io/"
+/// A failed `assert`, parsed from the program's stderr.
+struct AssertFailure {
+    /// The asserted expression, e.g. `x == 2`.
+    expr: String,
+    /// Where it failed, then V's `left value:` / `right value:` lines.
+    traceback: Vec<String>,
+    /// The rest of stderr, without the lines above.
+    rest: String,
+}
+
+/// Recognise V's report of a failed `assert`:
+///
+/// ```text
+/// line 3: FAIL: fn main.main: assert x == 2
+///    left value: x = 1
+///   right value: 2
+/// ```
+fn parse_assert_failure(stderr: &str) -> Option<AssertFailure> {
+    let lines: Vec<&str> = stderr.lines().collect();
+    let start = lines.iter().position(|l| l.contains(": FAIL: ") && l.contains("assert "))?;
+    let (location, rest) = lines[start].split_once(": FAIL: ")?;
+    let expr = rest.rsplit_once("assert ")?.1.trim().to_string();
+
+    let mut traceback = vec![format!("{}: assert {expr}", location.trim())];
+    let mut end = start + 1;
+    while let Some(line) = lines.get(end).map(|l| l.trim()) {
+        if !(line.starts_with("left value:") || line.starts_with("right value:") || line.starts_with("message:")) {
+            break;
+        }
+        traceback.push(line.to_string());
+        end += 1;
+    }
+
+    let mut rest: String = lines[..start]
+        .iter()
+        .chain(&lines[end..])
+        .map(|l| format!("{l}\n"))
+        .collect();
+    if rest.trim().is_empty() {
+        rest.clear();
+    }
+    Some(AssertFailure { expr, traceback, rest })
+}
+
+/// Number of `<kind>:` messages (`warning`, `error`) in V compiler output.
+fn count_diagnostics(text: &str, kind: &str) -> usize {
+    let marker = format!(" {kind}:");
+    text.lines().filter(|l| l.contains(&marker)).count()
+}
+
+/// The source of an `execute_request`: `content.code`, or — when that is
+/// empty and the message carries buffers — the first buffer as UTF-8.  Some
+/// front-ends send large cells that way to avoid JSON-escaping them.  Failing
+/// both, the file named by `metadata.file`, for "run this file" integrations.
+fn request_code(msg: &JupyterMessage) -> Result<String, String> {
+    let code = msg.content["code"].as_str().unwrap_or("");
+    if !code.is_empty() {
+        return Ok(code.to_string());
+    }
+    if let Some(buf) = msg.buffers.first() {
+        return Ok(match String::from_utf8(buf.clone()) {
+            Ok(code) => code,
+            Err(e) => {
+                log!("execute_request buffer is not valid UTF-8: {e}");
+                String::from_utf8_lossy(buf).into_owned()
             }
-        ]
-    })
+        });
+    }
+    match msg.metadata["file"].as_str() {
+        Some(path) => read_code_file(path),
+        None => Ok(String::new()),
+    }
+}
+
+/// Read the cell source at `path`, relative to the kernel's working
+/// directory.  The file must lie inside that directory once symlinks and
+/// `..` are resolved.
+fn read_code_file(path: &str) -> Result<String, String> {
+    let base = std::env::current_dir()
+        .and_then(fs::canonicalize)
+        .map_err(|e| format!("Cannot read {path}: no working directory: {e}\n"))?;
+    let resolved = fs::canonicalize(base.join(path)).map_err(|e| format!("Cannot read {path}: {e}\n"))?;
+    if !resolved.starts_with(&base) {
+        return Err(format!(
+            "Cannot read {path}: it is outside the kernel's working directory {}\n",
+            base.display()
+        ));
+    }
+    if !resolved.is_file() {
+        return Err(format!("Cannot read {path}: not a file\n"));
+    }
+    log!("execute_request runs {}", resolved.display());
+    fs::read_to_string(&resolved).map_err(|e| format!("Cannot read {path}: {e}\n"))
+}
+
+/// How long a restart waits for the worker to finish the cell it was running
+/// once that cell's processes have been killed.
+const RESTART_WAIT: Duration = Duration::from_secs(5);
+
+/// Lock `state`, giving up after `wait`.  The worker holds the lock for a
+/// whole cell, and the control channel must not hang behind it.
+fn lock_within(state: &Mutex<KernelState>, wait: Duration) -> Option<MutexGuard<'_, KernelState>> {
+    let deadline = Instant::now() + wait;
+    loop {
+        match state.try_lock() {
+            Ok(guard) => return Some(guard),
+            Err(TryLockError::Poisoned(e)) => panic!("kernel state lock poisoned: {e}"),
+            Err(TryLockError::WouldBlock) if Instant::now() >= deadline => return None,
+            Err(TryLockError::WouldBlock) => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+}
+
+/// Run cells from `jobs` one at a time, reporting each result on `done`.
+fn spawn_worker(
+    state: Arc<Mutex<KernelState>>,
+    jobs: mpsc::Receiver<ExecJob>,
+    done: mpsc::Sender<ExecDone>,
+) {
+    thread::spawn(move || {
+        for job in jobs {
+            let (output, execution_count) = {
+                let mut s = state.lock().unwrap();
+                let before = s.declarations.len();
+                let output = s.execute(&job.code);
+                if job.init && !output.is_error() {
+                    let added = s.declarations.get(before..).unwrap_or_default().to_vec();
+                    s.init_declarations.extend(added);
+                }
+                s.touch();
+                (output, s.execution_count)
+            };
+            let finished = ExecDone {
+                request: job.request,
+                output,
+                execution_count,
+            };
+            if done.send(finished).is_err() {
+                break;
+            }
+        }
+    });
 }
 
-// ── Main kernel loop ──────────────────────────────────────────────────────────
+// ── Main ──────────────────────────────────────────────────────────────────────
+
+/// Exit code used when a required port is already bound by another process.
+const EXIT_ADDR_IN_USE: i32 = 3;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: v-kernel <connection-file>");
-        std::process::exit(1);
+    let mut config = Config::from_env();
+
+    let mut connection_file = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--log-file" => match args.next() {
+                Some(path) => config.log_file = Some(PathBuf::from(path)),
+                None => usage(),
+            },
+            _ if connection_file.is_none() && !arg.starts_with("--") => connection_file = Some(arg),
+            _ => usage(),
+        }
     }
+    let Some(connection_file) = connection_file else {
+        usage();
+    };
 
-    let conn_json = fs::read_to_string(&args[1]).expect("Could not read connection file");
-    let conn: ConnectionInfo =
-        serde_json::from_str(&conn_json).expect("Invalid connection file JSON");
+    let connection_file = match resolve_connection_file(&connection_file) {
+        Ok(path) => path,
+        Err(e) => {
+            log!("Cannot use connection file {connection_file:?}: {e}");
+            std::process::exit(1);
+        }
+    };
+    let conn_json = match fs::read_to_string(&connection_file) {
+        Ok(json) => json,
+        Err(e) => {
+            log!("Could not read connection file {}: {e}", connection_file.display());
+            std::process::exit(1);
+        }
+    };
+    let conn: ConnectionInfo = match serde_json::from_str(&conn_json) {
+        Ok(conn) => conn,
+        Err(e) => {
+            log!("Invalid connection file JSON in {}: {e}", connection_file.display());
+            std::process::exit(1);
+        }
+    };
 
+    if let Err(e) = log::init(config.log_file.as_deref(), &conn.key) {
+        log!("Could not open log file: {e}; logging to stderr");
+    }
+    install_signal_handlers();
     let key = conn.key.as_bytes().to_vec();
     let session_id = Uuid::new_v4().to_string();
 
@@ -798,372 +3817,348 @@ fn main() {
 
     // ── Bind sockets ─────────────────────────────────────────────────────────
 
-    let shell = ctx.socket(SocketType::ROUTER).unwrap();
-    shell.bind(&conn.endpoint(conn.shell_port)).unwrap();
-
-    let iopub = ctx.socket(SocketType::PUB).unwrap();
-    iopub.bind(&conn.endpoint(conn.iopub_port)).unwrap();
-
-    let stdin = ctx.socket(SocketType::ROUTER).unwrap();
-    stdin.bind(&conn.endpoint(conn.stdin_port)).unwrap();
-
-    let control = ctx.socket(SocketType::ROUTER).unwrap();
-    control.bind(&conn.endpoint(conn.control_port)).unwrap();
-
-    let heartbeat = ctx.socket(SocketType::REP).unwrap();
-    heartbeat.bind(&conn.endpoint(conn.hb_port)).unwrap();
+    let shell = bind_required(&ctx, "shell", SocketType::ROUTER, &conn.endpoint(conn.shell_port));
+    let iopub = bind_required(&ctx, "iopub", SocketType::PUB, &conn.endpoint(conn.iopub_port));
+    let control = bind_required(&ctx, "control", SocketType::ROUTER, &conn.endpoint(conn.control_port));
+    let heartbeat = bind_required(&ctx, "heartbeat", SocketType::REP, &conn.endpoint(conn.hb_port));
+
+    // V programs never prompt through Jupyter, so the stdin socket is only
+    // bound for protocol compliance.  A failure to bind it (locked-down hosts)
+    // is logged and tolerated rather than fatal.
+    let stdin = if config.bind_stdin {
+        match bind_socket(&ctx, SocketType::ROUTER, &conn.endpoint(conn.stdin_port)) {
+            Ok(s) => Some(s),
+            Err(e) => {
+                log!("Could not bind stdin socket ({e}); continuing without it");
+                None
+            }
+        }
+    } else {
+        None
+    };
 
-    eprintln!("[v-kernel] Listening on all sockets. Session: {session_id}");
+    log!("Listening. Session: {session_id}");
 
     // ── Heartbeat thread ──────────────────────────────────────────────────────
     {
-        thread::spawn(move || loop {
-            if let Ok(msg) = heartbeat.recv_bytes(0) {
-                heartbeat.send(&msg, 0).ok();
-            }
-        });
+        let endpoint = conn.endpoint(conn.hb_port);
+        let handle = thread::spawn(move || run_heartbeat(heartbeat, &endpoint));
+        *HEARTBEAT.lock().unwrap() = Some(handle);
     }
 
     // ── Shared state ──────────────────────────────────────────────────────────
-    let state = Arc::new(Mutex::new(KernelState::new()));
-
-    let iopub = Arc::new(Mutex::new(iopub));
-
-    // ── Control thread ────────────────────────────────────────────────────────
-    {
-        let key = key.clone();
-        let session_id = session_id.clone();
+    let mut state = KernelState::new(config.clone(), &env::temp_dir());
+    let (live_tx, live_rx) = mpsc::channel();
+    if config.stream_window.is_some() {
+        state.live = Some(live_tx);
+    }
+    let running_pid = Arc::clone(&state.running_pid);
+    let tmp_dir = (!config.keep_tmp).then(|| state.tmp_dir.clone());
+    let state = Arc::new(Mutex::new(state));
+
+    // ── Idle watchdog ─────────────────────────────────────────────────────────
+    // Orphaned kernels (notebook closed, Zed crashed) otherwise live forever.
+    // The watchdog only runs when an idle timeout is configured.
+    if let Some(timeout) = config.idle_timeout {
         let state = Arc::clone(&state);
+        let running_pid = Arc::clone(&running_pid);
+        let tmp_dir = tmp_dir.clone();
         thread::spawn(move || loop {
-            if let Some(msg) = recv_message(&control, &key) {
-                let msg_type = msg.header["msg_type"]
-                    .as_str()
-                    .unwrap_or("")
-                    .to_string();
-
-                match msg_type.as_str() {
-                    "shutdown_request" => {
-                        let restart = msg.content["restart"].as_bool().unwrap_or(false);
-                        let reply = JupyterMessage {
-                            identities: msg.identities.clone(),
-                            header: make_header("shutdown_reply", &session_id),
-                            parent_header: msg.header.clone(),
-                            metadata: json!({}),
-                            content: json!({ "status": "ok", "restart": restart }),
-                            buffers: vec![],
-                        };
-                        send_message(&control, &reply, &key);
-                        eprintln!("[v-kernel] Shutdown requested. restart={restart}");
-                        if !restart {
-                            std::process::exit(0);
-                        }
-                    }
-                    "interrupt_request" => {
-                        let pid = state.lock().unwrap().running_pid;
-                        if let Some(pid) = pid {
-                            interrupt_process(pid);
-                            eprintln!("[v-kernel] Interrupted pid={pid}");
-                        } else {
-                            eprintln!("[v-kernel] interrupt_request but no child running");
-                        }
-                        let reply = JupyterMessage {
-                            identities: msg.identities.clone(),
-                            header: make_header("interrupt_reply", &session_id),
-                            parent_header: msg.header.clone(),
-                            metadata: json!({}),
-                            content: json!({ "status": "ok" }),
-                            buffers: vec![],
-                        };
-                        send_message(&control, &reply, &key);
-                    }
-                    _ => {
-                        eprintln!("[v-kernel] Unhandled control msg: {msg_type}");
-                    }
-                }
+            thread::sleep(timeout.min(Duration::from_secs(30)));
+            let idle = state.lock().unwrap().last_activity.elapsed();
+            if idle >= timeout {
+                log!(
+                    "No activity for {}s — shutting down idle kernel",
+                    idle.as_secs()
+                );
+                shutdown_kernel(&running_pid, tmp_dir.as_deref(), 0);
             }
         });
     }
 
-    // ── Shell loop ────────────────────────────────────────────────────────────
-    loop {
-        let msg = match recv_message(&shell, &key) {
-            Some(m) => m,
-            None => continue,
-        };
+    // ── Execution worker ──────────────────────────────────────────────────────
+    let (jobs_tx, jobs_rx) = mpsc::channel();
+    let (done_tx, done_rx) = mpsc::channel();
+    spawn_worker(Arc::clone(&state), jobs_rx, done_tx);
+
+    Kernel {
+        config,
+        key,
+        session_id,
+        shell,
+        control,
+        iopub,
+        stdin,
+        state,
+        running_pid,
+        tmp_dir,
+        jobs: jobs_tx,
+        done: done_rx,
+        live: live_rx,
+        live_parent: None,
+        busy: false,
+        iopub_failed: Cell::new(false),
+    }
+    .run()
+}
 
-        let msg_type = msg.header["msg_type"]
-            .as_str()
-            .unwrap_or("")
-            .to_string();
+// ── Helpers ───────────────────────────────────────────────────────────────────
 
-        eprintln!("[v-kernel] shell <- {msg_type}");
+/// Expand a leading `~`, `$HOME` or `${HOME}` in the connection-file
+/// argument and canonicalize it, so launchers may pass it in any form.
+fn resolve_connection_file(raw: &str) -> Result<PathBuf, String> {
+    let home_relative = ["~", "${HOME}", "$HOME"]
+        .iter()
+        .find_map(|prefix| raw.strip_prefix(prefix))
+        .filter(|rest| rest.is_empty() || rest.starts_with(['/', '\\']));
+
+    let path = match home_relative {
+        Some(rest) => {
+            let home = env::var_os("HOME")
+                .or_else(|| env::var_os("USERPROFILE"))
+                .ok_or("cannot expand the home directory: HOME is not set")?;
+            PathBuf::from(home).join(rest.trim_start_matches(['/', '\\']))
+        }
+        None => PathBuf::from(raw),
+    };
 
-        match msg_type.as_str() {
-            // ── kernel_info_request ──────────────────────────────────────────
-            "kernel_info_request" => {
-                let reply = JupyterMessage {
-                    identities: msg.identities.clone(),
-                    header: make_header("kernel_info_reply", &session_id),
-                    parent_header: msg.header.clone(),
-                    metadata: json!({}),
-                    content: kernel_info_content(),
-                    buffers: vec![],
-                };
-                send_message(&shell, &reply, &key);
-            }
+    fs::canonicalize(&path).map_err(|e| format!("{}: {e}", path.display()))
+}
 
-            // ── execute_request ──────────────────────────────────────────────
-            "execute_request" => {
-                let code = msg.content["code"]
-                    .as_str()
-                    .unwrap_or("")
-                    .to_string();
-                let silent = msg.content["silent"].as_bool().unwrap_or(false);
+fn usage() -> ! {
+    eprintln!("Usage: v-kernel [--log-file <path>] <connection-file>");
+    std::process::exit(1);
+}
 
-                let exec_count = {
-                    let s = state.lock().unwrap();
-                    s.execution_count + 1
-                };
+/// The `msg_type` from a message's header, or `""` if missing.
+fn msg_type(msg: &JupyterMessage) -> &str {
+    msg.header["msg_type"].as_str().unwrap_or("")
+}
 
-                if !silent {
-                    publish_status(&iopub, &key, &session_id, &msg, "busy");
-                }
+/// Create a socket of `kind` and bind it to `endpoint`.
+fn bind_socket(ctx: &Context, kind: SocketType, endpoint: &str) -> zmq::Result<Socket> {
+    let socket = ctx.socket(kind)?;
+    socket.bind(endpoint)?;
+    Ok(socket)
+}
 
-                if !silent {
-                    let input_msg = JupyterMessage {
-                        identities: vec![],
-                        header: make_header("execute_input", &session_id),
-                        parent_header: msg.header.clone(),
-                        metadata: json!({}),
-                        content: json!({
-                            "code": code,
-                            "execution_count": exec_count
-                        }),
-                        buffers: vec![],
-                    };
-                    let iopub = iopub.lock().unwrap();
-                    send_message(&iopub, &input_msg, &key);
-                }
+/// Bind a socket the kernel cannot work without, exiting with a readable
+/// message instead of a panic if that fails.
+///
+/// A port that is already in use almost always means an earlier kernel for
+/// the same connection file is still alive (e.g. Zed relaunched before the
+/// old process exited).  That case exits with `EXIT_ADDR_IN_USE` so the
+/// launcher can tell it apart and clean up the stale process.
+fn bind_required(ctx: &Context, name: &str, kind: SocketType, endpoint: &str) -> Socket {
+    bind_socket(ctx, kind, endpoint).unwrap_or_else(|e| {
+        if e == zmq::Error::EADDRINUSE {
+            log!(
+                "{name} socket {endpoint} is already in use — another kernel \
+                 appears to be running for this connection file"
+            );
+            std::process::exit(EXIT_ADDR_IN_USE);
+        }
+        log!("Could not bind {name} socket on {endpoint}: {e}");
+        std::process::exit(1);
+    })
+}
 
-                let (raw_stdout, stderr, is_error) = {
-                    let mut s = state.lock().unwrap();
-                    s.execute(&code)
-                };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                let final_exec_count = {
-                    let s = state.lock().unwrap();
-                    s.execution_count
-                };
+    /// A config whose `v` does not exist, so no test depends on (or waits
+    /// for) the installed toolchain.
+    fn test_config() -> Config {
+        Config {
+            v_binary: PathBuf::from("v-kernel-test-no-such-v"),
+            ..Config::default()
+        }
+    }
 
-                // ── Split dump() lines from stdout AND stderr ─────────────────
-                // V writes dump() output to stderr (not stdout). We intercept
-                // dump lines from both streams and merge them into a single
-                // HTML table, emitted before the plain text output.
-                let (plain_stdout, mut dump_entries) = split_dump_output(&raw_stdout);
-                let (plain_stderr, stderr_dump_entries) = if !is_error {
-                    split_dump_output(&stderr)
-                } else {
-                    // Don't strip dump lines from a genuine compiler error —
-                    // the whole stderr is the error message.
-                    (stderr.clone(), vec![])
-                };
-                dump_entries.extend(stderr_dump_entries);
-
-                // Publish plain stdout stream (non-dump lines)
-                if !plain_stdout.is_empty() && !silent {
-                    let stream_msg = JupyterMessage {
-                        identities: vec![],
-                        header: make_header("stream", &session_id),
-                        parent_header: msg.header.clone(),
-                        metadata: json!({}),
-                        content: json!({
-                            "name": "stdout",
-                            "text": plain_stdout
-                        }),
-                        buffers: vec![],
-                    };
-                    let iopub = iopub.lock().unwrap();
-                    send_message(&iopub, &stream_msg, &key);
-                }
+    /// A fresh directory for one test to create sessions in.
+    fn test_base() -> PathBuf {
+        let base = env::temp_dir().join(format!("v-kernel-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        base
+    }
 
-                // Publish dump() entries as rich HTML display_data
-                if !dump_entries.is_empty() && !silent {
-                    let html = render_dump_table(&dump_entries);
-                    // Plain-text fallback for non-HTML frontends.
-                    let plain_fallback = dump_entries
-                        .iter()
-                        .map(|e| {
-                            if e.typ.is_empty() {
-                                format!("[{}] {}: {}", e.location, e.name, e.value)
-                            } else {
-                                format!("[{}] {} = {}({})", e.location, e.name, e.typ, e.value)
-                            }
-                        })
-                        .collect::<Vec<_>>()
-                        .join("\n");
-
-                    let display_msg = JupyterMessage {
-                        identities: vec![],
-                        header: make_header("display_data", &session_id),
-                        parent_header: msg.header.clone(),
-                        metadata: json!({}),
-                        content: json!({
-                            "data": {
-                                "text/html": html,
-                                "text/plain": plain_fallback
-                            },
-                            "metadata": {}
-                        }),
-                        buffers: vec![],
-                    };
-                    let iopub = iopub.lock().unwrap();
-                    send_message(&iopub, &display_msg, &key);
-                }
+    #[test]
+    fn drop_removes_tmp_dir() {
+        let base = test_base();
+        let state = KernelState::new(test_config(), &base);
+        let tmp_dir = state.tmp_dir.clone();
+        assert!(tmp_dir.starts_with(&base));
+        assert!(tmp_dir.join("vkernel").join("vkernel.v").is_file());
+        drop(state);
+        assert!(!tmp_dir.exists());
+        fs::remove_dir_all(&base).ok();
+    }
 
-                // Publish stderr / error
-                // Use plain_stderr (dump lines already extracted above).
-                if is_error && !silent {
-                    let stream_msg = JupyterMessage {
-                        identities: vec![],
-                        header: make_header("stream", &session_id),
-                        parent_header: msg.header.clone(),
-                        metadata: json!({}),
-                        content: json!({
-                            "name": "stderr",
-                            "text": stderr  // full stderr for error messages
-                        }),
-                        buffers: vec![],
-                    };
-                    let iopub_lock = iopub.lock().unwrap();
-                    send_message(&iopub_lock, &stream_msg, &key);
-                    drop(iopub_lock);
-
-                    let error_msg = JupyterMessage {
-                        identities: vec![],
-                        header: make_header("error", &session_id),
-                        parent_header: msg.header.clone(),
-                        metadata: json!({}),
-                        content: json!({
-                            "ename": "CompileError",
-                            "evalue": "V compilation or runtime error",
-                            "traceback": stderr.lines().collect::<Vec<_>>()
-                        }),
-                        buffers: vec![],
-                    };
-                    let iopub_lock = iopub.lock().unwrap();
-                    send_message(&iopub_lock, &error_msg, &key);
-                } else if !plain_stderr.is_empty() && !silent {
-                    let stream_msg = JupyterMessage {
-                        identities: vec![],
-                        header: make_header("stream", &session_id),
-                        parent_header: msg.header.clone(),
-                        metadata: json!({}),
-                        content: json!({
-                            "name": "stderr",
-                            "text": plain_stderr  // dump lines stripped
-                        }),
-                        buffers: vec![],
-                    };
-                    let iopub = iopub.lock().unwrap();
-                    send_message(&iopub, &stream_msg, &key);
-                }
+    #[test]
+    fn keep_tmp_leaves_tmp_dir() {
+        let base = test_base();
+        let config = Config {
+            keep_tmp: true,
+            ..test_config()
+        };
+        let state = KernelState::new(config, &base);
+        let tmp_dir = state.tmp_dir.clone();
+        drop(state);
+        assert!(tmp_dir.is_dir());
+        fs::remove_dir_all(&base).ok();
+    }
 
-                // Send execute_reply
-                let reply_content = if is_error {
-                    json!({
-                        "status": "error",
-                        "execution_count": final_exec_count,
-                        "ename": "CompileError",
-                        "evalue": "V compilation or runtime error",
-                        "traceback": stderr.lines().collect::<Vec<_>>()
-                    })
-                } else {
-                    json!({
-                        "status": "ok",
-                        "execution_count": final_exec_count,
-                        "payload": [],
-                        "user_expressions": {}
-                    })
-                };
+    #[test]
+    fn collect_statement_splits_code_after_closing_brace() {
+        let lines = ["if ok {", "\tprintln(1)", "} x := 2"];
+        let (stmt, consumed, rest) = collect_statement(&lines, 0);
+        assert_eq!(stmt, "if ok {\n\tprintln(1)\n}");
+        assert_eq!(consumed, 3);
+        assert_eq!(rest, Some("x := 2"));
 
-                let reply = JupyterMessage {
-                    identities: msg.identities.clone(),
-                    header: make_header("execute_reply", &session_id),
-                    parent_header: msg.header.clone(),
-                    metadata: json!({}),
-                    content: reply_content,
-                    buffers: vec![],
-                };
-                send_message(&shell, &reply, &key);
+        let (_, stmts) = classify("for i in 0 .. 3 {\n\tprintln(i)\n} println('done')");
+        assert_eq!(stmts, ["for i in 0 .. 3 {\n\tprintln(i)\n}", "println('done')"]);
+    }
 
-                if !silent {
-                    publish_status(&iopub, &key, &session_id, &msg, "idle");
-                }
-            }
+    #[test]
+    fn collect_statement_keeps_else_and_or() {
+        let lines = ["if ok {", "\ta()", "} else {", "\tb()", "}", "c()"];
+        let (stmt, consumed, rest) = collect_statement(&lines, 0);
+        assert_eq!(stmt, lines[..5].join("\n"));
+        assert_eq!(consumed, 5);
+        assert_eq!(rest, None);
+
+        let lines = ["n := if ok {", "\t1", "} or {", "\t0", "}"];
+        let (stmt, consumed, rest) = collect_statement(&lines, 0);
+        assert_eq!(stmt, lines.join("\n"));
+        assert_eq!(consumed, 5);
+        assert_eq!(rest, None);
+    }
 
-            // ── is_complete_request ──────────────────────────────────────────
-            "is_complete_request" => {
-                let reply = JupyterMessage {
-                    identities: msg.identities.clone(),
-                    header: make_header("is_complete_reply", &session_id),
-                    parent_header: msg.header.clone(),
-                    metadata: json!({}),
-                    content: json!({ "status": "complete" }),
-                    buffers: vec![],
-                };
-                send_message(&shell, &reply, &key);
+    #[test]
+    fn strip_modifiers_in_any_order() {
+        let orders = ["", "pub ", "mut ", "static ", "pub mut ", "mut pub ", "pub static ", "static pub mut "];
+        for modifiers in orders {
+            for decl in ["fn f() {}", "const c = 1", "struct S {}"] {
+                assert_eq!(strip_modifiers(&format!("{modifiers}{decl}")), decl, "{modifiers:?}");
             }
+        }
+        assert_eq!(strip_modifiers("public := 1"), "public := 1");
+    }
 
-            // ── comm_info_request ────────────────────────────────────────────
-            "comm_info_request" => {
-                let reply = JupyterMessage {
-                    identities: msg.identities.clone(),
-                    header: make_header("comm_info_reply", &session_id),
-                    parent_header: msg.header.clone(),
-                    metadata: json!({}),
-                    content: json!({ "status": "ok", "comms": {} }),
-                    buffers: vec![],
-                };
-                send_message(&shell, &reply, &key);
+    #[test]
+    fn classify_modified_and_attributed_decls() {
+        let attributes = ["", "@[inline] ", "@[inline]\n", "@[heap] @[deprecated] ", "[heap]\n"];
+        let modifiers = ["", "pub ", "mut ", "static ", "pub mut ", "mut pub ", "static pub "];
+        let decls = ["fn f() int {\n\treturn 1\n}", "const c = 1", "struct S {\n\tx int\n}"];
+        for attribute in attributes {
+            for modifier in modifiers {
+                for decl in decls {
+                    let code = format!("{attribute}{modifier}{decl}");
+                    let (decls, stmts) = classify(&code);
+                    assert_eq!(decls, [code.as_str()], "{code:?}");
+                    assert!(stmts.is_empty(), "{code:?}");
+                }
             }
+        }
+    }
 
-            // ── history_request ──────────────────────────────────────────────
-            "history_request" => {
-                let reply = JupyterMessage {
-                    identities: msg.identities.clone(),
-                    header: make_header("history_reply", &session_id),
-                    parent_header: msg.header.clone(),
-                    metadata: json!({}),
-                    content: json!({ "status": "ok", "history": [] }),
-                    buffers: vec![],
-                };
-                send_message(&shell, &reply, &key);
-            }
+    #[test]
+    fn classify_generic_decls() {
+        let max = "fn max[T](a T, b T) T {\n\treturn if a > b { a } else { b }\n}";
+        let stack = "struct Stack[T] {\nmut:\n\titems []T\n}";
+        let peek = "fn (s Stack[T]) peek[T]() T {\n\treturn s.items[s.items.len - 1]\n}";
+        let push = "pub fn (mut s Stack[T]) push[T](x T) {\n\ts.items << x\n}";
+        let code = [max, stack, peek, push, "println(max[int](1, 2))"].join("\n");
+        let (decls, stmts) = classify(&code);
+        assert_eq!(decls, [max, stack, peek, push]);
+        assert_eq!(stmts, ["println(max[int](1, 2))"]);
+    }
 
-            other => {
-                eprintln!("[v-kernel] Unhandled shell msg type: {other}");
-            }
+    #[test]
+    fn generic_parameters_are_not_attributes() {
+        for line in ["fn max[T](a T, b T) T {", "struct Stack[T] {", "[1, 2].len()"] {
+            assert!(!is_attribute_line(line), "{line:?}");
         }
+        // A signature with `[T]` is one declaration, not an attribute that
+        // swallows the declaration after it.
+        let code = "fn first[T](a []T) T {\n\treturn a[0]\n}\nfn second() {}";
+        let (decls, _) = classify(code);
+        assert_eq!(decls, ["fn first[T](a []T) T {\n\treturn a[0]\n}", "fn second() {}"]);
     }
-}
 
-// ── Helpers ───────────────────────────────────────────────────────────────────
+    #[test]
+    fn build_source_is_deterministic() {
+        let base = test_base();
+        let declarations = [
+            "import os",
+            "struct Point {\n\tx int\n\ty int\n}",
+            "import math",
+            "fn (p Point) norm() f64 {\n\treturn math.sqrt(f64(p.x * p.x + p.y * p.y))\n}",
+            "import os",
+        ];
+        let stmts = ["p := Point{3, 4}".to_string(), "println(p.norm())".to_string()];
+        let sources: Vec<String> = (0..2)
+            .map(|_| {
+                let mut state = KernelState::new(test_config(), &base);
+                state.declarations = declarations.iter().map(|d| d.to_string()).collect();
+                let first = state.build_source(&stmts, CellMode::Module, &["import vkernel"]);
+                assert_eq!(first, state.build_source(&stmts, CellMode::Module, &["import vkernel"]));
+                first
+            })
+            .collect();
+        assert_eq!(sources[0].as_bytes(), sources[1].as_bytes());
+        assert_eq!(sources[0].matches("import os").count(), 1);
+        fs::remove_dir_all(&base).ok();
+    }
 
-fn publish_status(
-    iopub: &Arc<Mutex<Socket>>,
-    key: &[u8],
-    session_id: &str,
-    parent: &JupyterMessage,
-    execution_state: &str,
-) {
-    let status_msg = JupyterMessage {
-        identities: vec![],
-        header: make_header("status", session_id),
-        parent_header: parent.header.clone(),
-        metadata: json!({}),
-        content: json!({ "execution_state": execution_state }),
-        buffers: vec![],
-    };
-    let iopub = iopub.lock().unwrap();
-    send_message(&iopub, &status_msg, key);
+    /// A stand-in for `v` that "compiles" a cell into a shell script printing
+    /// one result line per `vkernel.echo('', <expr>)` call in it, with the
+    /// expression's source as the value.  Anything but a build succeeds
+    /// silently, which covers the capability probes.
+    #[cfg(unix)]
+    const FAKE_V: &str = r#"#!/bin/sh
+[ "$1" = "-o" ] || exit 0
+bin=$2
+src=$3
+{
+    echo '#!/bin/sh'
+    sed -n "s/^.*vkernel\.echo('', \(.*\))\$/\1/p" "$src" | while IFS= read -r expr; do
+        echo "echo '%vkernel-result $(printf '%s' "$expr" | base64)'"
+    done
+} > "$bin"
+chmod +x "$bin"
+"#;
+
+    #[cfg(unix)]
+    #[test]
+    fn one_result_per_executed_cell() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let base = test_base();
+        let v = base.join("fake-v");
+        fs::write(&v, FAKE_V).unwrap();
+        fs::set_permissions(&v, fs::Permissions::from_mode(0o755)).unwrap();
+        let config = Config {
+            v_binary: v,
+            max_processes: 16,
+            ..Config::default()
+        };
+        let mut state = KernelState::new(config, &base);
+
+        let cells = [
+            ("fn one() int {\n\treturn 1\n}\nreturn one()", "one()"),
+            ("x := one() + 1\nreturn x", "x"),
+            ("return one() * 3", "one() * 3"),
+        ];
+        for (code, expected) in cells {
+            let out = state.execute(code);
+            assert!(!out.is_error(), "{code:?}: {}", out.stderr);
+            let results = split_display_output(&out.stdout).results;
+            assert_eq!(results, [expected], "{code:?}");
+        }
+        drop(state);
+        fs::remove_dir_all(&base).ok();
+    }
 }
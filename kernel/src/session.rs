@@ -0,0 +1,167 @@
+//! Shared sessions.
+//!
+//! Kernels started with the same `V_KERNEL_SESSION` name keep their
+//! accumulated declarations in one file, `declarations.json` under
+//! `<sessions>/<name>/`, so a struct declared in one notebook can be used
+//! from another.  Without a session name every kernel is fully isolated, as
+//! before.
+//!
+//! The declarations are compiled and run, so the directories must belong to
+//! this user alone: on Unix `<sessions>` is `$XDG_RUNTIME_DIR/v-kernel-sessions`,
+//! or `<temp>/v-kernel-sessions-<uid>`, and a directory that is not owned by
+//! the user or that others can access is refused rather than used.
+//!
+//! There is no lock: each kernel pulls the file before a cell and pushes its
+//! declarations after it.  When another kernel wrote in between, the push
+//! keeps the other kernel's list and appends this kernel's new declarations,
+//! so concurrent cells never drop each other's work.
+
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub struct SharedSession {
+    name: String,
+    file: PathBuf,
+    /// The declaration list as last read from or written to `file`.
+    seen: Vec<String>,
+}
+
+impl SharedSession {
+    /// Join (creating if needed) the session called `name`.
+    pub fn open(name: &str) -> io::Result<Self> {
+        if !valid_name(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid session name {name:?}"),
+            ));
+        }
+        let base = sessions_dir();
+        private_dir(&base)?;
+        let dir = base.join(name);
+        private_dir(&dir)?;
+        Ok(SharedSession {
+            name: name.to_string(),
+            file: dir.join("declarations.json"),
+            seen: Vec::new(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Forget what was last seen, so the next `pull` takes the session's
+    /// declarations again (after a restart).
+    pub fn rejoin(&mut self) {
+        self.seen.clear();
+    }
+
+    /// The session's declarations, if another kernel changed them since this
+    /// one last looked.
+    pub fn pull(&mut self) -> io::Result<Option<Vec<String>>> {
+        let current = read_declarations(&self.file)?;
+        if current == self.seen {
+            return Ok(None);
+        }
+        self.seen = current.clone();
+        Ok(Some(current))
+    }
+
+    /// Publish `declarations`.  Returns the list actually written, which
+    /// differs from `declarations` when another kernel wrote in the meantime.
+    pub fn push(&mut self, declarations: &[String]) -> io::Result<Vec<String>> {
+        if declarations == self.seen {
+            return Ok(declarations.to_vec());
+        }
+        let current = read_declarations(&self.file)?;
+        let merged = if current == self.seen {
+            declarations.to_vec()
+        } else {
+            let mut merged = current;
+            for decl in declarations {
+                if !self.seen.contains(decl) && !merged.contains(decl) {
+                    merged.push(decl.clone());
+                }
+            }
+            merged
+        };
+        write_declarations(&self.file, &merged)?;
+        self.seen = merged.clone();
+        Ok(merged)
+    }
+}
+
+/// Whether `name` can be used as a session directory name.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Where sessions are kept: a directory of this user's own.
+fn sessions_dir() -> PathBuf {
+    #[cfg(unix)]
+    {
+        match env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+            Some(dir) => PathBuf::from(dir).join("v-kernel-sessions"),
+            None => {
+                // SAFETY: getuid takes no arguments and cannot fail.
+                let uid = unsafe { libc::getuid() };
+                env::temp_dir().join(format!("v-kernel-sessions-{uid}"))
+            }
+        }
+    }
+    // The temp directory is already per-user on Windows.
+    #[cfg(not(unix))]
+    env::temp_dir().join("v-kernel-sessions")
+}
+
+/// Create `dir` if it is missing and check that only this user can use it:
+/// a real directory (not a link), owned by the user, with no access for
+/// group or others.
+#[cfg(unix)]
+fn private_dir(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    match fs::DirBuilder::new().mode(0o700).create(dir) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+    let meta = fs::symlink_metadata(dir)?;
+    // SAFETY: getuid takes no arguments and cannot fail.
+    let uid = unsafe { libc::getuid() };
+    if !meta.is_dir() || meta.uid() != uid || meta.mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not a private directory of this user", dir.display()),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn private_dir(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)
+}
+
+fn read_declarations(path: &Path) -> io::Result<Vec<String>> {
+    match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).map_err(io::Error::other),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Write through a temporary file and rename it into place, so readers never
+/// see a half-written list.
+fn write_declarations(path: &Path, declarations: &[String]) -> io::Result<()> {
+    let tmp = path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+    fs::write(&tmp, serde_json::to_vec_pretty(declarations)?)?;
+    fs::rename(&tmp, path).inspect_err(|_| {
+        fs::remove_file(&tmp).ok();
+    })
+}